@@ -1,16 +1,82 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::mem;
 use machine;
+use unicode;
+use parser::{GrammarError, Span};
 
 #[derive(Debug)]
 pub struct Grammar {
-    pub rules : Vec<Pattern>,
-    pub main : u32
+    pub rules : Vec<Rule>,
+    pub main : u32,
+    pub skip : Option<Rule>,
+    pub utf8 : bool,
+    pub imports : Vec<Import>,
+    pub labels : Vec<String>,
+    pub captures : Vec<String>,
+    pub comments : Vec<Comment>
 }
 
 #[derive(Debug)]
+pub struct Rule {
+    pub name : String,
+    pub span : Span,
+    pub module : Option<String>,
+    pub parameters : Vec<String>,
+    // A silent rule is matched without recording its span, and an inline
+    // rule is spliced into every rule that refers to it.
+    pub silent : bool,
+    pub inline : bool,
+    pub pattern : Pattern,
+    pub comments : Vec<Comment>
+}
+
+/// An `import "path" as name` statement, whose rules are referred to as
+/// `name.rule`.
+#[derive(Debug)]
+pub struct Import {
+    pub path : String,
+    pub name : String,
+    pub span : Span
+}
+
+/// A comment kept so that the grammar can be printed again. Within a rule,
+/// line 0 holds the rule name, lines from 1 hold its top-level alternatives
+/// and any later line is the closing brace. For the grammar itself, line 0 is
+/// the top of the file and line 1 its end. A trailing comment is printed
+/// after the text of its line rather than before it.
+#[derive(Debug, Clone)]
+pub struct Comment {
+    pub line : usize,
+    pub trailing : bool,
+    pub text : String
+}
+
+/// How an operator written with `@left(n)`, `@right(n)` or `@nonassoc(n)`
+/// groups with operators at its own level. A level of an operator table may
+/// also hold `@prefix` or `@postfix` operators.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Fixity {
+    Left,
+    Right,
+    NonAssoc,
+    Prefix,
+    Postfix
+}
+
+/// A part of a character class as it was written.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ClassItem {
+    Range(char, Option<char>),
+    /// A shorthand such as `\d` or `\p{L}`, or a named class such as
+    /// `[:alpha:]`, with the ranges it stands for.
+    Named(String, Vec<(char, Option<char>)>)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Pattern {
-    CharClass(Vec<(u8, Option<u8>)>),
-    CharSequence(Vec<u8>),
+    CharClass(Vec<(char, Option<char>)>, bool, bool),
+    CharSequence(Vec<u8>, bool),
     CharAny,
     Variable(i32, i32, usize, bool),
     Choice(Box<Pattern>, Box<Pattern>),
@@ -18,39 +84,96 @@ pub enum Pattern {
     OneOrMore(Box<Pattern>),
     Sequence(Vec<Box<Pattern>>),
     Optional(Box<Pattern>),
-    Lookahead(bool, Box<Pattern>)
+    Lookahead(bool, Box<Pattern>),
+    Atomic(Box<Pattern>),
+    Repeat(Box<Pattern>, u32, Option<u32>),
+    Cut(Span),
+    Label(Box<Pattern>, String, usize),
+    Recover(Box<Pattern>, Box<Pattern>, Vec<String>, u64),
+    Capture(Box<Pattern>, String, usize),
+    BackReference(String, usize, Span),
+    Reference(String, i32, Span),
+    Apply(String, Vec<Box<Pattern>>, Span),
+    // The forms below are kept by the parser so that a grammar prints as it
+    // was written, and are replaced by `desugar` before it is compiled.
+    Class(Vec<ClassItem>, bool, bool),
+    Shorthand(String, Vec<(char, Option<char>)>),
+    Operators(Box<Pattern>, Vec<(Fixity, Vec<Box<Pattern>>)>),
+    Operator(Fixity, i32, Vec<Box<Pattern>>)
 }
 
+// Recoveries keep the labels they catch in a bit set.
+const LABEL_LIMIT : usize = 64;
+
+// Bounds on the instances created by expanding parameterized rules, beyond
+// which the expansion is assumed not to terminate.
+const EXPANSION_DEPTH : usize = 16;
+const EXPANSION_COUNT : usize = 10000;
+
 impl Grammar {
     pub fn compile(&mut self) -> Vec<machine::Instruction> {
         let mut rules = Vec::new();
         let mut lookup = vec![];
 
+        self.decode();
+        self.inline_rules();
         self.name_variables();
         let left_recursive_calls = self.discover_left_recursion();
         self.label_variables(&left_recursive_calls);
 
-        for p in &self.rules {
-            rules.push(Grammar::compile_pattern(p));
+        // Inline rules are spliced into the rules that use them, so their own
+        // bodies are never called.
+        for rule in &self.rules {
+            if rule.inline {
+                rules.push(vec![machine::Instruction::Fail]);
+            } else {
+                rules.push(Grammar::compile_pattern(&rule.pattern));
+            }
         }
 
         let mut result = vec![
             machine::Instruction::Call(self.main as isize),
+            machine::Instruction::Skip,
             machine::Instruction::Stop
         ];
 
-        let mut k = 2isize;
+        if self.skip.is_some() {
+            result.push(machine::Instruction::Call(self.rules.len() as isize));
+            result.push(machine::Instruction::Stop);
+        }
+
+        // Every rule has an entry stub of its own, laid out as in `entries`.
+        for id in 0..self.rules.len() {
+            result.push(machine::Instruction::Call(id as isize));
+            result.push(machine::Instruction::Skip);
+            result.push(machine::Instruction::Stop);
+        }
+
+        let mut k = result.len() as isize;
         let mut id = 0;
         for mut rule in rules {
             lookup.push(k);
             k += 3 + rule.len() as isize;
-            result.push(machine::Instruction::PushPos(id));
+            if self.rules[id].silent || self.rules[id].inline {
+                result.push(machine::Instruction::PushSilent);
+            } else {
+                result.push(machine::Instruction::PushPos(id));
+            }
             result.append(&mut rule);
             result.push(machine::Instruction::SavePos);
             result.push(machine::Instruction::Return);
             id += 1;
         }
 
+        // The skip rule is called between tokens and consumes as much trivia
+        // as it can, so it is compiled as a repetition and never captured.
+        if let Some(ref skip) = self.skip {
+            lookup.push(k);
+            let mut rule = Grammar::compile_zero_or_more(Grammar::compile_pattern(&skip.pattern));
+            result.append(&mut rule);
+            result.push(machine::Instruction::Return);
+        }
+
         for i in 0..result.len() {
             if let machine::Instruction::Call(r) = result[i] {
                 let dist = lookup[r as usize] - i as isize;
@@ -64,49 +187,967 @@ impl Grammar {
         result
     }
 
+    /// The names captured for each rule, where an instance of a parameterized
+    /// rule is captured under the name of that rule.
+    pub fn rule_names(&self) -> Vec<String> {
+        self.rules.iter().map(|x| Grammar::template_name(&x.name).to_string()).collect()
+    }
+
+    fn template_name(name : &str) -> &str {
+        name.split('<').next().unwrap_or(name)
+    }
+
+    /// Replaces every use of a parameterized rule with a reference to an
+    /// instance of it, created once for each distinct list of arguments.
+    pub fn expand(&mut self) -> Result<(), Vec<GrammarError>> {
+        let mut errors = vec![];
+        let mut templates = HashMap::new();
+        let mut rules = vec![];
+        // Where each name is first defined and whether that is a template.
+        // Plain rules defined twice are left for `resolve` to report.
+        let mut defined : HashMap<String, (Span, bool)> = HashMap::new();
+        for rule in self.rules.drain(..) {
+            let template = !rule.parameters.is_empty();
+            match defined.get(&rule.name) {
+                Some(&(span, other)) if template || other => {
+                    errors.push(Grammar::duplicate_error(&rule, span));
+                    continue;
+                },
+                Some(_) => { },
+                None => {
+                    defined.insert(rule.name.clone(), (rule.span, template));
+                }
+            }
+            if template {
+                templates.insert(rule.name.clone(), rule);
+            } else {
+                rules.push(rule);
+            }
+        }
+
+        let plain : HashSet<String> = rules.iter().map(|x| x.name.clone()).collect();
+        let mut instances = vec![];
+        let mut seen = HashMap::new();
+        {
+            let mut expansion = Expansion {
+                templates: &templates,
+                plain: &plain,
+                instances: &mut instances,
+                seen: &mut seen,
+                errors: &mut errors
+            };
+            for rule in &mut rules {
+                expansion.expand_pattern(&mut rule.pattern, 0, &rule.name, &rule.module);
+            }
+            if let Some(ref mut skip) = self.skip {
+                expansion.expand_pattern(&mut skip.pattern, 0, &skip.name, &skip.module);
+            }
+        }
+        rules.append(&mut instances);
+        self.rules = rules;
+
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+
+    // Rules made by the builder have no position, so theirs is left out.
+    fn duplicate_error(rule : &Rule, first : Span) -> GrammarError {
+        let mut message = format!("rule `{}` is defined more than once", rule.name);
+        if first != Span::default() {
+            message.push_str(&format!(", first at {}:{}", first.line, first.column));
+        }
+        GrammarError {
+            module: rule.module.clone(),
+            span: rule.span,
+            rule: Some(rule.name.clone()),
+            expected: None,
+            message
+        }
+    }
+
+    /// Binds every reference to the rule it names and sets the main rule.
+    /// All undefined and duplicate rules are reported at once.
+    pub fn resolve(&mut self) -> Result<(), Vec<GrammarError>> {
+        let mut errors = vec![];
+        let mut ids : HashMap<String, usize> = HashMap::new();
+
+        for (id, rule) in self.rules.iter().enumerate() {
+            if let Some(&first) = ids.get(&rule.name) {
+                errors.push(Grammar::duplicate_error(rule, self.rules[first].span));
+            } else {
+                ids.insert(rule.name.clone(), id);
+            }
+        }
+
+        match ids.get("main") {
+            // The machine starts by calling `main`, which an inline rule has
+            // no body for.
+            Some(&id) if self.rules[id].inline => {
+                let rule = &self.rules[id];
+                errors.push(GrammarError {
+                    module: rule.module.clone(),
+                    span: rule.span,
+                    rule: Some(rule.name.clone()),
+                    expected: None,
+                    message: "the `main` rule cannot be inline".to_string()
+                });
+            },
+            Some(&id) => self.main = id as u32,
+            None => {
+                errors.push(GrammarError {
+                    module: None,
+                    span: Span { line: 1, column: 1 },
+                    rule: None,
+                    expected: None,
+                    message: "the grammar has no `main` rule".to_string()
+                });
+            }
+        }
+
+        let inline : Vec<bool> = self.rules.iter().map(|x| x.inline).collect();
+        for rule in &mut self.rules {
+            Grammar::resolve_pattern(&mut rule.pattern, &rule.name, &rule.module, &ids, &inline, &mut errors);
+        }
+        if let Some(ref mut skip) = self.skip {
+            Grammar::resolve_pattern(&mut skip.pattern, &skip.name, &skip.module, &ids, &inline, &mut errors);
+        }
+
+        let (mut labels, mut captures) = (vec![], vec![]);
+        for rule in &mut self.rules {
+            Grammar::number_names(&mut rule.pattern, &mut labels, &mut captures);
+        }
+        if let Some(ref mut skip) = self.skip {
+            Grammar::number_names(&mut skip.pattern, &mut labels, &mut captures);
+        }
+        for rule in self.rules.iter_mut().chain(self.skip.as_mut()) {
+            let mut own = vec![];
+            Grammar::captured_names(&rule.pattern, &mut own);
+            Grammar::bind_back_references(&mut rule.pattern, &rule.name, &rule.module, &captures, &own, &mut errors);
+        }
+        if labels.len() > LABEL_LIMIT {
+            errors.push(GrammarError {
+                module: None,
+                span: Span { line: 1, column: 1 },
+                rule: None,
+                expected: None,
+                message: format!("the grammar has more than {} failure labels", LABEL_LIMIT)
+            });
+        }
+        self.labels = labels;
+        self.captures = captures;
+
+        for rule in &self.rules {
+            Grammar::check_cuts(&rule.pattern, false, &rule.name, &rule.module, &mut errors);
+        }
+        if let Some(ref skip) = self.skip {
+            Grammar::check_cuts(&skip.pattern, false, &skip.name, &skip.module, &mut errors);
+        }
+
+        // Splicing a rule into itself would never end.
+        if errors.is_empty() {
+            for (id, rule) in self.rules.iter().enumerate() {
+                if rule.inline && self.inlines_into(&rule.pattern, id, &mut HashSet::new()) {
+                    errors.push(GrammarError {
+                        module: rule.module.clone(),
+                        span: rule.span,
+                        rule: Some(Grammar::template_name(&rule.name).to_string()),
+                        expected: None,
+                        message: format!("the inline rule `{}` refers to itself",
+                            Grammar::template_name(&rule.name))
+                    });
+                }
+            }
+        }
+
+        // A skip rule that matches nothing would be repeated forever.
+        if errors.is_empty() {
+            if let Some(ref skip) = self.skip {
+                if self.nullable(&skip.pattern) {
+                    errors.push(GrammarError {
+                        module: skip.module.clone(),
+                        span: skip.span,
+                        rule: Some(skip.name.clone()),
+                        expected: None,
+                        message: "the skip rule must not match empty input".to_string()
+                    });
+                }
+            }
+        }
+
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+
+    /// Moves the rules of an imported module into this grammar, naming them
+    /// and every reference between them under the module's prefix.
+    pub fn import(&mut self, module : Grammar, prefix : &str) {
+        for mut rule in module.rules {
+            rule.name = format!("{}{}", prefix, rule.name);
+            rule.parameters = rule.parameters.iter().map(|x| format!("{}{}", prefix, x)).collect();
+            Grammar::qualify_pattern(&mut rule.pattern, prefix);
+            self.rules.push(rule);
+        }
+    }
+
+    fn qualify_pattern(pattern : &mut Pattern, prefix : &str) {
+        use self::Pattern::*;
+        match *pattern {
+            Reference(ref mut name, _, _) => {
+                *name = format!("{}{}", prefix, name);
+            },
+            Apply(ref mut name, ref mut data, _) => {
+                *name = format!("{}{}", prefix, name);
+                for pattern in data {
+                    Grammar::qualify_pattern(pattern, prefix);
+                }
+            },
+            Choice(ref mut le, ref mut ri) | Recover(ref mut le, ref mut ri, _, _) => {
+                Grammar::qualify_pattern(le, prefix);
+                Grammar::qualify_pattern(ri, prefix);
+            },
+            Sequence(ref mut data) => {
+                for pattern in data {
+                    Grammar::qualify_pattern(pattern, prefix);
+                }
+            },
+            ZeroOrMore(ref mut data) | OneOrMore(ref mut data) | Optional(ref mut data)
+            | Lookahead(_, ref mut data) | Atomic(ref mut data) | Repeat(ref mut data, _, _)
+            | Label(ref mut data, _, _) | Capture(ref mut data, _, _) => {
+                Grammar::qualify_pattern(data, prefix);
+            },
+            _ => { }
+        }
+    }
+
+    /// Turns the forms the parser keeps for printing, namely class
+    /// shorthands, operators and operator tables, into the patterns they
+    /// stand for. The operands of an operator are given their precedences,
+    /// and a non-associative operator is followed by a lookahead over every
+    /// non-associative operator of its level in the same choice, so that
+    /// none of them can follow it.
+    pub fn desugar(&mut self) -> Result<(), Vec<GrammarError>> {
+        let mut errors = vec![];
+        for rule in self.rules.iter_mut().chain(self.skip.as_mut()) {
+            if let Err(error) = Grammar::desugar_pattern(&mut rule.pattern, &rule.name, rule.span, None) {
+                errors.push(error);
+            }
+        }
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+
+    fn desugar_pattern(pattern : &mut Pattern,
+        rule : &str,
+        span : Span,
+        operators : Option<&[(i32, Pattern)]>)
+        -> Result<(), GrammarError>
+    {
+        use self::Pattern::*;
+        let desugared = match *pattern {
+            Class(ref items, negated, insensitive) => {
+                let mut ranges = vec![];
+                for item in items {
+                    match *item {
+                        ClassItem::Range(first, last) => ranges.push((first, last)),
+                        ClassItem::Named(_, ref data) => ranges.extend_from_slice(data)
+                    }
+                }
+                CharClass(ranges, negated, insensitive)
+            },
+            Shorthand(_, ref ranges) => CharClass(ranges.clone(), false, false),
+            Operators(ref operand, ref levels) => {
+                let mut table = Grammar::operator_table(operand, levels, rule, span);
+                Grammar::desugar_pattern(&mut table, rule, span, None)?;
+                table
+            },
+            Choice(ref mut le, ref mut ri) => {
+                let mut found = vec![];
+                let operators = match operators {
+                    Some(x) => x,
+                    None => {
+                        Grammar::nonassoc_operators(le, &mut found);
+                        Grammar::nonassoc_operators(ri, &mut found);
+                        &found[..]
+                    }
+                };
+                Grammar::desugar_pattern(le, rule, span, Some(operators))?;
+                Grammar::desugar_pattern(ri, rule, span, Some(operators))?;
+                return Ok(());
+            },
+            Operator(fixity, level, ref mut data) => {
+                let mut found = vec![];
+                let operators = match operators {
+                    Some(x) => x,
+                    None => {
+                        found.push((level, Grammar::operator_of(data)));
+                        &found[..]
+                    }
+                };
+                let (left, right) = match fixity {
+                    Fixity::Right => (level + 1, level),
+                    _ => (level, level + 1)
+                };
+                let last = data.len() - 1;
+                for &(index, precedence) in &[(0, left), (last, right)] {
+                    if let Reference(ref name, ref mut x, span) = *data[index] {
+                        if *x != -1 {
+                            continue;
+                        }
+                        if name != rule {
+                            return Err(GrammarError {
+                                module: None,
+                                span,
+                                rule: Some(rule.to_string()),
+                                expected: None,
+                                message: format!("the operands of rule `{}` must refer to `{}`, found `{}`", rule, rule, name)
+                            });
+                        }
+                        *x = precedence;
+                    }
+                }
+                for pattern in data.iter_mut() {
+                    Grammar::desugar_pattern(pattern, rule, span, None)?;
+                }
+                if fixity == Fixity::NonAssoc {
+                    let mut followers = operators.iter()
+                        .filter(|x| x.0 == level)
+                        .map(|x| x.1.clone())
+                        .collect::<Vec<_>>();
+                    let mut follower = followers.pop().unwrap();
+                    while let Some(x) = followers.pop() {
+                        follower = Choice(Box::new(x), Box::new(follower));
+                    }
+                    data.push(Box::new(Lookahead(false, Box::new(follower))));
+                }
+                Sequence(mem::take(data))
+            },
+            Recover(ref mut le, ref mut ri, _, _) => {
+                Grammar::desugar_pattern(le, rule, span, None)?;
+                Grammar::desugar_pattern(ri, rule, span, None)?;
+                return Ok(());
+            },
+            Sequence(ref mut data) | Apply(_, ref mut data, _) => {
+                for pattern in data {
+                    Grammar::desugar_pattern(pattern, rule, span, None)?;
+                }
+                return Ok(());
+            },
+            ZeroOrMore(ref mut data) | OneOrMore(ref mut data) | Optional(ref mut data)
+            | Lookahead(_, ref mut data) | Atomic(ref mut data) | Repeat(ref mut data, _, _)
+            | Label(ref mut data, _, _) | Capture(ref mut data, _, _) => {
+                return Grammar::desugar_pattern(data, rule, span, None);
+            },
+            _ => return Ok(())
+        };
+        *pattern = desugared;
+        Ok(())
+    }
+
+    // The levels of an operator table become alternatives of the rule,
+    // loosest first, followed by the operand. Each level takes two precedence
+    // numbers so that the operands of one level never admit the operators of
+    // the same or a looser level. The references to the rule itself are
+    // placed at the rule's name.
+    fn operator_table(operand : &Pattern,
+        levels : &[(Fixity, Vec<Box<Pattern>>)],
+        rule : &str,
+        span : Span)
+        -> Pattern
+    {
+        let reference = |precedence| Box::new(Pattern::Reference(rule.to_string(), precedence, span));
+        let mut pattern = operand.clone();
+        for (k, &(fixity, ref operators)) in levels.iter().enumerate().rev() {
+            let mut operators = operators.clone();
+            let mut operator = operators.pop().unwrap();
+            while let Some(previous) = operators.pop() {
+                operator = Box::new(Pattern::Choice(previous, operator));
+            }
+            let n = 2 * k as i32 + 1;
+            let data = match fixity {
+                Fixity::Left => vec![reference(n), operator, reference(n + 1)],
+                Fixity::Right => vec![reference(n + 1), operator, reference(n)],
+                Fixity::NonAssoc => {
+                    let lookahead = Box::new(Pattern::Lookahead(false, operator.clone()));
+                    vec![reference(n), operator, reference(n + 1), lookahead]
+                },
+                Fixity::Prefix => vec![operator, reference(n)],
+                Fixity::Postfix => vec![reference(n), operator]
+            };
+            pattern = Pattern::Choice(Box::new(Pattern::Sequence(data)), Box::new(pattern));
+        }
+        pattern
+    }
+
+    // The non-associative operators among the alternatives of a choice, with
+    // their levels.
+    fn nonassoc_operators(pattern : &Pattern, operators : &mut Vec<(i32, Pattern)>) {
+        match *pattern {
+            Pattern::Choice(ref le, ref ri) => {
+                Grammar::nonassoc_operators(le, operators);
+                Grammar::nonassoc_operators(ri, operators);
+            },
+            Pattern::Operator(Fixity::NonAssoc, level, ref data) => {
+                operators.push((level, Grammar::operator_of(data)));
+            },
+            _ => { }
+        }
+    }
+
+    // The operator of a non-associative alternative is everything between
+    // its two operands.
+    fn operator_of(data : &[Box<Pattern>]) -> Pattern {
+        let operator = &data[1..data.len() - 1];
+        if operator.len() == 1 {
+            (*operator[0]).clone()
+        } else {
+            Pattern::Sequence(operator.to_vec())
+        }
+    }
+
+    fn resolve_pattern(pattern : &mut Pattern,
+        rule : &str,
+        module : &Option<String>,
+        ids : &HashMap<String, usize>,
+        inline : &[bool],
+        errors : &mut Vec<GrammarError>)
+    {
+        use self::Pattern::*;
+        let id = match *pattern {
+            Reference(ref name, precedence, span) => {
+                match ids.get(name) {
+                    // An inline rule is spliced in rather than called, so it
+                    // cannot be called at a precedence either.
+                    Some(&id) if inline[id] && precedence != -1 => {
+                        errors.push(GrammarError {
+                            module: module.clone(),
+                            span,
+                            rule: Some(Grammar::template_name(rule).to_string()),
+                            expected: None,
+                            message: format!("the inline rule `{}` cannot take a precedence", name)
+                        });
+                        return;
+                    },
+                    Some(&id) => id,
+                    None => {
+                        let mut message = format!("rule `{}` is not defined", name);
+                        if let Some(suggestion) = Grammar::suggest(name, ids) {
+                            message = format!("{}, did you mean `{}`?", message, suggestion);
+                        }
+                        errors.push(GrammarError {
+                            module: module.clone(),
+                            span,
+                            rule: Some(Grammar::template_name(rule).to_string()),
+                            expected: None,
+                            message
+                        });
+                        return;
+                    }
+                }
+            },
+            Choice(ref mut le, ref mut ri) | Recover(ref mut le, ref mut ri, _, _) => {
+                Grammar::resolve_pattern(le, rule, module, ids, inline, errors);
+                Grammar::resolve_pattern(ri, rule, module, ids, inline, errors);
+                return;
+            },
+            Sequence(ref mut data) => {
+                for pattern in data {
+                    Grammar::resolve_pattern(pattern, rule, module, ids, inline, errors);
+                }
+                return;
+            },
+            ZeroOrMore(ref mut data) | OneOrMore(ref mut data) | Optional(ref mut data)
+            | Lookahead(_, ref mut data) | Atomic(ref mut data) | Repeat(ref mut data, _, _)
+            | Label(ref mut data, _, _) | Capture(ref mut data, _, _) => {
+                Grammar::resolve_pattern(data, rule, module, ids, inline, errors);
+                return;
+            },
+            _ => return
+        };
+        if let Reference(_, precedence, _) = *pattern {
+            *pattern = Variable(id as i32, precedence, 0, false);
+        }
+    }
+
+    // A cut commits to the alternative of the choice it is written in, so it
+    // has to be an element of that alternative rather than nested in some
+    // other pattern, where it would have nothing to commit to.
+    fn check_cuts(pattern : &Pattern,
+        alternative : bool,
+        rule : &str,
+        module : &Option<String>,
+        errors : &mut Vec<GrammarError>)
+    {
+        use self::Pattern::*;
+        match *pattern {
+            Cut(span) if !alternative => {
+                errors.push(GrammarError {
+                    module: module.clone(),
+                    span,
+                    rule: Some(Grammar::template_name(rule).to_string()),
+                    expected: None,
+                    message: "a cut must be in an alternative of a choice".to_string()
+                });
+            },
+            Choice(ref le, ref ri) => {
+                Grammar::check_cuts(le, true, rule, module, errors);
+                Grammar::check_cuts(ri, true, rule, module, errors);
+            },
+            Sequence(ref data) => {
+                for pattern in data {
+                    Grammar::check_cuts(pattern, alternative, rule, module, errors);
+                }
+            },
+            Recover(ref le, ref ri, _, _) => {
+                Grammar::check_cuts(le, false, rule, module, errors);
+                Grammar::check_cuts(ri, false, rule, module, errors);
+            },
+            ZeroOrMore(ref data) | OneOrMore(ref data) | Optional(ref data)
+            | Lookahead(_, ref data) | Atomic(ref data) | Repeat(ref data, _, _)
+            | Label(ref data, _, _) | Capture(ref data, _, _) => {
+                Grammar::check_cuts(data, false, rule, module, errors);
+            },
+            _ => { }
+        }
+    }
+
+    // Labels and named captures are numbered in the order they first appear.
+    fn number_names(pattern : &mut Pattern, labels : &mut Vec<String>, captures : &mut Vec<String>) {
+        use self::Pattern::*;
+        match *pattern {
+            Label(ref mut data, ref name, ref mut id) => {
+                *id = Grammar::name_id(name, labels);
+                Grammar::number_names(data, labels, captures);
+            },
+            Capture(ref mut data, ref name, ref mut id) => {
+                *id = Grammar::name_id(name, captures);
+                Grammar::number_names(data, labels, captures);
+            },
+            Recover(ref mut le, ref mut ri, ref names, ref mut set) => {
+                *set = 0;
+                for name in names {
+                    let id = Grammar::name_id(name, labels);
+                    if id < LABEL_LIMIT {
+                        *set |= 1 << id;
+                    }
+                }
+                Grammar::number_names(le, labels, captures);
+                Grammar::number_names(ri, labels, captures);
+            },
+            Choice(ref mut le, ref mut ri) => {
+                Grammar::number_names(le, labels, captures);
+                Grammar::number_names(ri, labels, captures);
+            },
+            Sequence(ref mut data) => {
+                for pattern in data {
+                    Grammar::number_names(pattern, labels, captures);
+                }
+            },
+            ZeroOrMore(ref mut data) | OneOrMore(ref mut data) | Optional(ref mut data)
+            | Lookahead(_, ref mut data) | Atomic(ref mut data) | Repeat(ref mut data, _, _) => {
+                Grammar::number_names(data, labels, captures);
+            },
+            _ => { }
+        }
+    }
+
+    fn name_id(name : &String, names : &mut Vec<String>) -> usize {
+        match names.iter().position(|x| x == name) {
+            Some(id) => id,
+            None => {
+                names.push(name.clone());
+                names.len() - 1
+            }
+        }
+    }
+
+    // The names captured in a pattern, not counting the rules it calls.
+    fn captured_names(pattern : &Pattern, names : &mut Vec<String>) {
+        use self::Pattern::*;
+        match *pattern {
+            Capture(ref data, ref name, _) => {
+                names.push(name.clone());
+                Grammar::captured_names(data, names);
+            },
+            Choice(ref le, ref ri) | Recover(ref le, ref ri, _, _) => {
+                Grammar::captured_names(le, names);
+                Grammar::captured_names(ri, names);
+            },
+            Sequence(ref data) => {
+                for pattern in data {
+                    Grammar::captured_names(pattern, names);
+                }
+            },
+            ZeroOrMore(ref data) | OneOrMore(ref data) | Optional(ref data)
+            | Lookahead(_, ref data) | Atomic(ref data) | Repeat(ref data, _, _)
+            | Label(ref data, _, _) => {
+                Grammar::captured_names(data, names);
+            },
+            _ => { }
+        }
+    }
+
+    // The captures of a rule are dropped when it returns, so a back-reference
+    // can only match the text of a capture made by its own rule. The names are
+    // bound to the numbers of every capture in the grammar.
+    fn bind_back_references(pattern : &mut Pattern,
+        rule : &str,
+        module : &Option<String>,
+        captures : &Vec<String>,
+        own : &Vec<String>,
+        errors : &mut Vec<GrammarError>)
+    {
+        use self::Pattern::*;
+        match *pattern {
+            BackReference(ref name, ref mut id, span) => {
+                let message = match captures.iter().position(|x| x == name) {
+                    Some(x) if own.contains(name) => {
+                        *id = x;
+                        return;
+                    },
+                    Some(_) => format!("capture `{}` is made by another rule, not by rule `{}`",
+                        name, Grammar::template_name(rule)),
+                    None => format!("capture `{}` is not defined", name)
+                };
+                errors.push(GrammarError {
+                    module: module.clone(),
+                    span,
+                    rule: Some(Grammar::template_name(rule).to_string()),
+                    expected: None,
+                    message
+                });
+            },
+            Choice(ref mut le, ref mut ri) | Recover(ref mut le, ref mut ri, _, _) => {
+                Grammar::bind_back_references(le, rule, module, captures, own, errors);
+                Grammar::bind_back_references(ri, rule, module, captures, own, errors);
+            },
+            Sequence(ref mut data) => {
+                for pattern in data {
+                    Grammar::bind_back_references(pattern, rule, module, captures, own, errors);
+                }
+            },
+            ZeroOrMore(ref mut data) | OneOrMore(ref mut data) | Optional(ref mut data)
+            | Lookahead(_, ref mut data) | Atomic(ref mut data) | Repeat(ref mut data, _, _)
+            | Label(ref mut data, _, _) | Capture(ref mut data, _, _) => {
+                Grammar::bind_back_references(data, rule, module, captures, own, errors);
+            },
+            _ => { }
+        }
+    }
+
+    // The closest defined name, if it is close enough to be a likely typo.
+    fn suggest<'a>(name : &str, ids : &'a HashMap<String, usize>) -> Option<&'a String> {
+        let limit = ::std::cmp::max(1, name.chars().count() / 3);
+        ids.keys()
+            .map(|x| (Grammar::edit_distance(name, x), x))
+            .filter(|x| x.0 <= limit)
+            .min()
+            .map(|x| x.1)
+    }
+
+    // The number of insertions, deletions, substitutions and transpositions of
+    // adjacent characters needed to turn one name into the other.
+    fn edit_distance(a : &str, b : &str) -> usize {
+        let a : Vec<char> = a.chars().collect();
+        let b : Vec<char> = b.chars().collect();
+        let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+        for (i, row) in d.iter_mut().enumerate() { row[0] = i; }
+        for (j, x) in d[0].iter_mut().enumerate() { *x = j; }
+        for i in 1..a.len() + 1 {
+            for j in 1..b.len() + 1 {
+                let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+                d[i][j] = ::std::cmp::min(d[i - 1][j - 1] + cost,
+                    ::std::cmp::min(d[i - 1][j], d[i][j - 1]) + 1);
+                if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                    d[i][j] = ::std::cmp::min(d[i][j], d[i - 2][j - 2] + 1);
+                }
+            }
+        }
+        d[a.len()][b.len()]
+    }
+
+    /// The entry point of the skip routine, which directly follows the entry
+    /// point of the main rule.
+    pub fn skip_entry(&self) -> Option<usize> {
+        self.skip.as_ref().map(|_| 3)
+    }
+
+    /// The entry point of every rule that can be executed on its own, which
+    /// excludes the instances of parameterized rules and inline rules.
+    pub fn entries(&self) -> HashMap<String, usize> {
+        let start = 3 + self.skip_entry().map_or(0, |_| 2);
+        self.rules.iter()
+            .enumerate()
+            .filter(|&(_, rule)| rule.parameters.is_empty() && !rule.name.contains('<') && !rule.inline)
+            .map(|(id, rule)| (rule.name.clone(), start + 3 * id))
+            .collect()
+    }
+
+    /// Whether the pattern can succeed without consuming any input.
+    pub fn nullable(&self, pattern : &Pattern) -> bool {
+        self.nullable_pattern(pattern, &mut HashSet::new())
+    }
+
+    fn nullable_pattern(&self, pattern : &Pattern, visited : &mut HashSet<i32>) -> bool {
+        use self::Pattern::*;
+        match *pattern {
+            CharClass(_, _, _) | CharAny | Reference(_, _, _) | Apply(_, _, _)
+            | Class(_, _, _) | Shorthand(_, _) | Operators(_, _) => false,
+            CharSequence(ref data, _) => data.is_empty(),
+            Cut(_) | BackReference(_, _, _) => true,
+            Variable(r, _, _, _) => {
+                if visited.insert(r) {
+                    let result = self.nullable_pattern(&self.rules[r as usize].pattern, visited);
+                    visited.remove(&r);
+                    result
+                } else {
+                    false
+                }
+            },
+            Choice(ref le, ref ri) | Recover(ref le, ref ri, _, _) => {
+                self.nullable_pattern(le, visited) || self.nullable_pattern(ri, visited)
+            },
+            ZeroOrMore(_) | Optional(_) | Lookahead(_, _) => true,
+            OneOrMore(ref data) | Atomic(ref data) | Label(ref data, _, _)
+            | Capture(ref data, _, _) => self.nullable_pattern(data, visited),
+            Repeat(ref data, min, _) => min == 0 || self.nullable_pattern(data, visited),
+            Sequence(ref data) | Operator(_, _, ref data) => data.iter().all(|p| self.nullable_pattern(p, visited))
+        }
+    }
+
+    /// Terminals are the token boundaries of a grammar, so every terminal is
+    /// preceded by a `Skip` which runs the skip routine when skipping is on.
+    /// A repeated terminal is a single token, see `compile_token_repeat`.
     pub fn compile_pattern(p : &Pattern) -> Vec<machine::Instruction> {
         match p {
-            &Pattern::CharClass(ref data) => Grammar::compile_char_class(data),
-            &Pattern::CharSequence(ref data) => Grammar::compile_char_sequence(data),
+            &Pattern::ZeroOrMore(ref data) if Grammar::is_terminal(data) => Grammar::compile_token_repeat(data, 0, None),
+            &Pattern::OneOrMore(ref data) if Grammar::is_terminal(data) => Grammar::compile_token_repeat(data, 1, None),
+            &Pattern::Repeat(ref data, min, max) if Grammar::is_terminal(data) && max != Some(0) => {
+                Grammar::compile_token_repeat(data, min, max)
+            },
+            &Pattern::CharClass(ref data, negated, insensitive) => Grammar::compile_char_class(data, negated, insensitive),
+            &Pattern::CharSequence(ref data, insensitive) => Grammar::compile_char_sequence(data, insensitive),
             &Pattern::CharAny => Grammar::compile_char_any(),
             &Pattern::Variable(id, precedence, _, is_left) => Grammar::compile_variable(id, precedence, is_left),
             &Pattern::Choice(ref le, ref ri) => Grammar::compile_choice(le, ri),
-            &Pattern::ZeroOrMore(ref data) => Grammar::compile_zero_or_more(data),
-            &Pattern::OneOrMore(ref data) => Grammar::compile_one_or_more(data),
+            &Pattern::ZeroOrMore(ref data) => Grammar::compile_zero_or_more(Grammar::compile_pattern(data)),
+            &Pattern::OneOrMore(ref data) => Grammar::compile_one_or_more(Grammar::compile_pattern(data)),
             &Pattern::Sequence(ref data) => Grammar::compile_sequence(data),
             &Pattern::Optional(ref data) => Grammar::compile_optional(data),
-            &Pattern::Lookahead(flag, ref data) => Grammar::compile_lookahead(flag, data)
+            &Pattern::Lookahead(flag, ref data) => Grammar::compile_lookahead(flag, data),
+            Pattern::Atomic(data) => Grammar::compile_atomic(data),
+            &Pattern::Repeat(ref data, min, max) => Grammar::compile_repeat(Grammar::compile_pattern(data), min, max),
+            // A cut only has an effect in an alternative of a choice, which
+            // `compile_alternative` takes care of, and `resolve` rejects it
+            // anywhere else. A cut in the last alternative has no effect.
+            &Pattern::Cut(_) => vec![],
+            &Pattern::Label(ref data, _, label) => Grammar::compile_label(data, label),
+            &Pattern::Recover(ref le, ref ri, _, labels) => Grammar::compile_recover(le, ri, labels),
+            &Pattern::Capture(ref data, _, id) => Grammar::compile_capture(data, id),
+            &Pattern::BackReference(_, id, _) => vec![machine::Instruction::Skip, machine::Instruction::BackReference(id)],
+            // References are bound to rules by `expand` and `resolve`, and the
+            // written forms are replaced by `desugar`, before compiling.
+            &Pattern::Reference(_, _, _) | &Pattern::Apply(_, _, _) | &Pattern::Class(_, _, _)
+            | &Pattern::Shorthand(_, _) | &Pattern::Operators(_, _)
+            | &Pattern::Operator(_, _, _) => vec![machine::Instruction::Fail]
         }
     }
 
-    fn compile_char_class(data : &Vec<(u8, Option<u8>)>) -> Vec<machine::Instruction> {
-        let mut result = vec![];
-        let mut jump = data.len();
-        for range in data.iter().take(data.len() - 1) {
-            let left = (*range).0;
-            let right = (*range).1.unwrap_or(left);
-            result.push(machine::Instruction::CharRangeLink(left, right, jump as isize));
-            jump -= 1;
-        }
-        
-        if let Some(last) = data.iter().last() {
-            let left = (*last).0;
-            let right = (*last).1.unwrap_or(left);
-            result.push(machine::Instruction::CharRange(left, right));
+    // A negated class matches any code point outside of its ranges, after
+    // the ranges have been case folded. Byte mode negates classes by `decode`.
+    fn compile_char_class(data : &[(char, Option<char>)], negated : bool, insensitive : bool)
+        -> Vec<machine::Instruction>
+    {
+        let mut ranges : Vec<(u32, u32)> = data.iter()
+            .map(|&(left, right)| (left as u32, right.unwrap_or(left) as u32))
+            .collect();
+        if insensitive {
+            ranges = Grammar::fold_ranges(ranges);
+        }
+        if negated {
+            ranges = Grammar::complement(ranges);
         }
+        let mut result = vec![machine::Instruction::Skip];
+        result.append(&mut Grammar::compile_ranges(ranges));
         result
     }
 
-    fn compile_char_sequence(data : &Vec<u8>) -> Vec<machine::Instruction> {
+    // Ranges of single bytes are linked together, while every range of
+    // multi-byte sequences is its own alternative matched byte by byte.
+    fn compile_ranges(ranges : Vec<(u32, u32)>) -> Vec<machine::Instruction> {
+        let mut sequences = vec![];
+        for (left, right) in ranges {
+            Grammar::utf8_sequences(left, right, &mut sequences);
+        }
+        let (bytes, sequences) : (Vec<_>, Vec<_>) = sequences.drain(..)
+            .partition(|x| x.len() == 1);
+
+        let mut alternatives = vec![];
+        if !bytes.is_empty() {
+            let mut links = vec![];
+            let mut jump = bytes.len();
+            for range in bytes.iter().take(bytes.len() - 1) {
+                links.push(machine::Instruction::CharRangeLink(range[0].0, range[0].1, jump as isize));
+                jump -= 1;
+            }
+            if let Some(last) = bytes.iter().last() {
+                links.push(machine::Instruction::CharRange(last[0].0, last[0].1));
+            }
+            alternatives.push(links);
+        }
+        for sequence in sequences {
+            alternatives.push(sequence.iter()
+                .map(|&(l, r)| machine::Instruction::CharRange(l, r))
+                .collect());
+        }
+
+        let mut choice = alternatives.pop().unwrap_or(vec![machine::Instruction::Fail]);
+        while let Some(mut alternative) = alternatives.pop() {
+            let mut inner = vec![machine::Instruction::Choice(alternative.len() as isize + 2)];
+            inner.append(&mut alternative);
+            inner.push(machine::Instruction::Commit(choice.len() as isize + 1));
+            inner.append(&mut choice);
+            choice = inner;
+        }
+        choice
+    }
+
+    // Adds every code point linked to the ranges through the case pairs, in
+    // either direction and in turn, so that `k`, `K` and the Kelvin sign all
+    // fold together whichever of them is written.
+    fn fold_ranges(ranges : Vec<(u32, u32)>) -> Vec<(u32, u32)> {
+        let pairs = |left : u32, right : u32| {
+            unicode::CASE_PAIRS.iter().filter_map(move |&(from, to)| {
+                if left <= from && from <= right {
+                    Some(to)
+                } else if left <= to && to <= right {
+                    Some(from)
+                } else {
+                    None
+                }
+            })
+        };
+        let mut result = ranges.clone();
+        let mut pending : Vec<u32> = ranges.iter().flat_map(|&(left, right)| pairs(left, right)).collect();
+        let mut seen = HashSet::new();
+        while let Some(folded) = pending.pop() {
+            if ranges.iter().any(|&(left, right)| left <= folded && folded <= right) || !seen.insert(folded) {
+                continue;
+            }
+            result.push((folded, folded));
+            pending.extend(pairs(folded, folded));
+        }
+        Grammar::merge(result)
+    }
+
+    fn merge(mut ranges : Vec<(u32, u32)>) -> Vec<(u32, u32)> {
+        ranges.sort();
+        let mut result : Vec<(u32, u32)> = vec![];
+        for (left, right) in ranges {
+            if left > right {
+                continue;
+            }
+            match result.last_mut() {
+                Some(last) if left <= last.1 + 1 => {
+                    last.1 = ::std::cmp::max(last.1, right);
+                    continue;
+                },
+                _ => { }
+            }
+            result.push((left, right));
+        }
+        result
+    }
+
+    fn complement(ranges : Vec<(u32, u32)>) -> Vec<(u32, u32)> {
         let mut result = vec![];
-        for c in data {
-            result.push(machine::Instruction::Char(*c));
+        let mut next = 0;
+        for (left, right) in Grammar::merge(ranges) {
+            if left > next {
+                result.push((next, left - 1));
+            }
+            next = ::std::cmp::max(next, right + 1);
+        }
+        if next <= ::std::char::MAX as u32 {
+            result.push((next, ::std::char::MAX as u32));
+        }
+        result
+    }
+
+    // Splits a range of scalar values into ranges of UTF-8 sequences such that
+    // every byte of a sequence can be matched by a single byte range.
+    fn utf8_sequences(start : u32, end : u32, result : &mut Vec<Vec<(u8, u8)>>) {
+        if start > end {
+            return;
+        }
+        if start <= 0xDFFF && end >= 0xD800 {
+            Grammar::utf8_sequences(start, 0xD7FF, result);
+            Grammar::utf8_sequences(0xE000, end, result);
+            return;
+        }
+        for &max in &[0x7F, 0x7FF, 0xFFFF] {
+            if start <= max && max < end {
+                Grammar::utf8_sequences(start, max, result);
+                Grammar::utf8_sequences(max + 1, end, result);
+                return;
+            }
+        }
+        for i in 1..4 {
+            let mask = (1 << (6 * i)) - 1;
+            if start & !mask != end & !mask {
+                if start & mask != 0 {
+                    Grammar::utf8_sequences(start, start | mask, result);
+                    Grammar::utf8_sequences((start | mask) + 1, end, result);
+                    return;
+                }
+                if end & mask != mask {
+                    Grammar::utf8_sequences(start, (end & !mask) - 1, result);
+                    Grammar::utf8_sequences(end & !mask, end, result);
+                    return;
+                }
+            }
+        }
+        let (mut left_buffer, mut right_buffer) = ([0; 4], [0; 4]);
+        let left = ::std::char::from_u32(start).unwrap().encode_utf8(&mut left_buffer).as_bytes();
+        let right = ::std::char::from_u32(end).unwrap().encode_utf8(&mut right_buffer).as_bytes();
+        result.push(left.iter().cloned().zip(right.iter().cloned()).collect());
+    }
+
+    // Case-insensitive ASCII letters are matched by folding the input, while
+    // other cased characters match any of their case mappings.
+    fn compile_char_sequence(data : &Vec<u8>, insensitive : bool) -> Vec<machine::Instruction> {
+        let mut result = vec![machine::Instruction::Skip];
+        let text = match ::std::str::from_utf8(data) {
+            Ok(text) if insensitive => text,
+            _ => {
+                for c in data {
+                    if insensitive && c.is_ascii_alphabetic() {
+                        result.push(machine::Instruction::CharFold(c.to_ascii_lowercase()));
+                    } else {
+                        result.push(machine::Instruction::Char(*c));
+                    }
+                }
+                return result;
+            }
+        };
+        // Letters fold through the same case pairs as classes do, so that
+        // `'k'i` also matches the Kelvin sign. ASCII letters with no other
+        // case than their own pair fold with a single instruction.
+        for c in text.chars() {
+            let folded = Grammar::fold_ranges(vec![(c as u32, c as u32)]);
+            let pair = folded.iter().map(|x| x.1 - x.0 + 1).sum::<u32>() == 2;
+            if c.is_ascii_alphabetic() && pair {
+                result.push(machine::Instruction::CharFold(c.to_ascii_lowercase() as u8));
+            } else if folded != [(c as u32, c as u32)] {
+                result.append(&mut Grammar::compile_ranges(folded));
+            } else {
+                let mut buffer = [0; 4];
+                for b in c.encode_utf8(&mut buffer).as_bytes() {
+                    result.push(machine::Instruction::Char(*b));
+                }
+            }
         }
         result
     }
 
     fn compile_char_any() -> Vec<machine::Instruction> {
-        vec![machine::Instruction::Any]
+        vec![machine::Instruction::Skip, machine::Instruction::Any]
     }
 
     fn compile_variable(id : i32, precedence : i32, is_left : bool) -> Vec<machine::Instruction> {
@@ -117,19 +1158,73 @@ impl Grammar {
         }
     }
 
+    // Once the left alternative has been cut its backtrack entry is gone, so
+    // the alternative ends with a jump rather than a commit.
     fn compile_choice(left : &Box<Pattern>, right : &Box<Pattern>) -> Vec<machine::Instruction> {
-        let mut inner_left = Grammar::compile_pattern(left);
+        let (mut inner_left, cut) = Grammar::compile_alternative(left, false);
         let mut inner_right = Grammar::compile_pattern(right);
         let mut result = vec![];
         result.push(machine::Instruction::Choice(inner_left.len() as isize + 2));
         result.append(&mut inner_left);
+        if cut {
+            result.push(machine::Instruction::Jump(inner_right.len() as isize + 1));
+        } else {
+            result.push(machine::Instruction::Commit(inner_right.len() as isize + 1));
+        }
+        result.append(&mut inner_right);
+        result
+    }
+
+    // The first cut in the sequence of an alternative discards the backtrack
+    // entry of the choice, which is on top of the stack between the elements
+    // of the sequence.
+    fn compile_alternative(p : &Pattern, mut cut : bool) -> (Vec<machine::Instruction>, bool) {
+        match *p {
+            Pattern::Cut(_) if !cut => (vec![machine::Instruction::Commit(1)], true),
+            Pattern::Sequence(ref data) => {
+                let mut result = vec![];
+                for p in data {
+                    let (mut inner, inner_cut) = Grammar::compile_alternative(p, cut);
+                    result.append(&mut inner);
+                    cut = inner_cut;
+                }
+                (result, cut)
+            },
+            _ => (Grammar::compile_pattern(p), cut)
+        }
+    }
+
+    fn compile_label(data : &Pattern, label : usize) -> Vec<machine::Instruction> {
+        let mut inner = Grammar::compile_pattern(data);
+        let mut result = vec![];
+        result.push(machine::Instruction::Choice(inner.len() as isize + 2));
+        result.append(&mut inner);
+        result.push(machine::Instruction::Commit(2));
+        result.push(machine::Instruction::Throw(label));
+        result
+    }
+
+    // Trivia is skipped before the mark so that the captured text starts at
+    // the first token of the pattern, as a back-reference to it does.
+    fn compile_capture(data : &Pattern, id : usize) -> Vec<machine::Instruction> {
+        let mut result = vec![machine::Instruction::Skip, machine::Instruction::Mark];
+        result.append(&mut Grammar::compile_pattern(data));
+        result.push(machine::Instruction::Capture(id));
+        result
+    }
+
+    fn compile_recover(left : &Pattern, right : &Pattern, labels : u64) -> Vec<machine::Instruction> {
+        let mut inner_left = Grammar::compile_pattern(left);
+        let mut inner_right = Grammar::compile_pattern(right);
+        let mut result = vec![];
+        result.push(machine::Instruction::Recover(inner_left.len() as isize + 2, labels));
+        result.append(&mut inner_left);
         result.push(machine::Instruction::Commit(inner_right.len() as isize + 1));
         result.append(&mut inner_right);
         result
     }
 
-    fn compile_zero_or_more(data : &Box<Pattern>) -> Vec<machine::Instruction> {
-        let mut inner = Grammar::compile_pattern(data);
+    fn compile_zero_or_more(mut inner : Vec<machine::Instruction>) -> Vec<machine::Instruction> {
         let instr_count = inner.len() as isize;
         let mut result = vec![];
         result.push(machine::Instruction::Choice(instr_count + 2));
@@ -138,8 +1233,7 @@ impl Grammar {
         result
     }
 
-    fn compile_one_or_more(data : &Box<Pattern>) -> Vec<machine::Instruction> {
-        let mut inner = Grammar::compile_pattern(data);
+    fn compile_one_or_more(mut inner : Vec<machine::Instruction>) -> Vec<machine::Instruction> {
         let mut inner_clone = inner.clone();
         let instr_count = inner.len() as isize;
         let mut result = vec![];
@@ -188,10 +1282,194 @@ impl Grammar {
         result
     }
 
+    // The count of repetitions lives in a counter frame on the stack, so the
+    // program stays the same size no matter how large the bounds are.
+    fn compile_repeat(inner : Vec<machine::Instruction>, min : u32, max : Option<u32>) -> Vec<machine::Instruction> {
+        let instr_count = inner.len() as isize;
+        let mut result = vec![machine::Instruction::PushCounter];
+
+        if min > 0 {
+            result.push(machine::Instruction::TestCounter(min as usize, instr_count + 3));
+            result.append(&mut inner.clone());
+            result.push(machine::Instruction::IncrementCounter);
+            result.push(machine::Instruction::Jump(-(instr_count + 2)));
+        }
+
+        match max {
+            Some(max) if max <= min => { },
+            Some(max) => {
+                result.push(machine::Instruction::TestCounter(max as usize, instr_count + 5));
+                result.push(machine::Instruction::Choice(instr_count + 4));
+                result.append(&mut inner.clone());
+                result.push(machine::Instruction::Commit(1));
+                result.push(machine::Instruction::IncrementCounter);
+                result.push(machine::Instruction::Jump(-(instr_count + 4)));
+            },
+            None => {
+                result.append(&mut Grammar::compile_zero_or_more(inner));
+            }
+        }
+        result.push(machine::Instruction::PopCounter);
+        result
+    }
+
+    fn is_terminal(p : &Pattern) -> bool {
+        matches!(*p, Pattern::CharClass(_, _, _) | Pattern::CharSequence(_, _) | Pattern::CharAny
+            | Pattern::BackReference(_, _, _))
+    }
+
+    // Trivia is skipped before the first repetition of a terminal but never
+    // between repetitions, so that `[a-z]+` does not match `ab cd`. When the
+    // terminal may match nothing the trivia is only consumed along with it.
+    fn compile_token_repeat(data : &Pattern, min : u32, max : Option<u32>) -> Vec<machine::Instruction> {
+        // Every terminal starts with its `Skip`, which is hoisted out of the loop.
+        let mut inner = Grammar::compile_pattern(data);
+        inner.remove(0);
+        let mut result = vec![machine::Instruction::Skip];
+        match max {
+            None if min <= 1 => result.append(&mut Grammar::compile_one_or_more(inner)),
+            _ => result.append(&mut Grammar::compile_repeat(inner, min.max(1), max))
+        }
+        if min == 0 {
+            result.insert(0, machine::Instruction::Choice(result.len() as isize + 2));
+            result.push(machine::Instruction::Commit(1));
+        }
+        result
+    }
+
+    // An atomic pattern is a single token, so trivia is skipped before it but
+    // never inside of it.
+    fn compile_atomic(data : &Pattern) -> Vec<machine::Instruction> {
+        let mut result = vec![];
+        let mut inner = Grammar::compile_pattern(data);
+        result.push(machine::Instruction::Skip);
+        result.push(machine::Instruction::PushSkip(false));
+        result.append(&mut inner);
+        result.push(machine::Instruction::PopSkip);
+        result
+    }
+
+    // Whether the pattern reaches the given rule through inline rules only.
+    fn inlines_into(&self, pattern : &Pattern, target : usize, visited : &mut HashSet<usize>) -> bool {
+        use self::Pattern::*;
+        match *pattern {
+            Variable(r, _, _, _) => {
+                let r = r as usize;
+                if r == target {
+                    true
+                } else if self.rules[r].inline && visited.insert(r) {
+                    self.inlines_into(&self.rules[r].pattern, target, visited)
+                } else {
+                    false
+                }
+            },
+            Choice(ref le, ref ri) | Recover(ref le, ref ri, _, _) => {
+                self.inlines_into(le, target, visited) || self.inlines_into(ri, target, visited)
+            },
+            Sequence(ref data) => data.iter().any(|p| self.inlines_into(p, target, visited)),
+            ZeroOrMore(ref data) | OneOrMore(ref data) | Optional(ref data)
+            | Lookahead(_, ref data) | Atomic(ref data) | Repeat(ref data, _, _)
+            | Label(ref data, _, _) | Capture(ref data, _, _) => {
+                self.inlines_into(data, target, visited)
+            },
+            _ => false
+        }
+    }
+
+    // Replaces every reference to an inline rule with its body. The inline
+    // rules are still compiled, but are never called.
+    fn inline_rules(&mut self) {
+        let bodies : Vec<Option<Pattern>> = self.rules.iter()
+            .map(|x| if x.inline { Some(x.pattern.clone()) } else { None })
+            .collect();
+        for rule in &mut self.rules {
+            Grammar::inline_pattern(&mut rule.pattern, &bodies);
+        }
+        if let Some(ref mut skip) = self.skip {
+            Grammar::inline_pattern(&mut skip.pattern, &bodies);
+        }
+    }
+
+    fn inline_pattern(pattern : &mut Pattern, bodies : &Vec<Option<Pattern>>) {
+        use self::Pattern::*;
+        let mut body = match *pattern {
+            Variable(r, _, _, _) => {
+                match bodies[r as usize] {
+                    Some(ref body) => body.clone(),
+                    None => return
+                }
+            },
+            Choice(ref mut le, ref mut ri) | Recover(ref mut le, ref mut ri, _, _) => {
+                Grammar::inline_pattern(le, bodies);
+                Grammar::inline_pattern(ri, bodies);
+                return;
+            },
+            Sequence(ref mut data) => {
+                for pattern in data {
+                    Grammar::inline_pattern(pattern, bodies);
+                }
+                return;
+            },
+            ZeroOrMore(ref mut data) | OneOrMore(ref mut data) | Optional(ref mut data)
+            | Lookahead(_, ref mut data) | Atomic(ref mut data) | Repeat(ref mut data, _, _)
+            | Label(ref mut data, _, _) | Capture(ref mut data, _, _) => {
+                Grammar::inline_pattern(data, bodies);
+                return;
+            },
+            _ => return
+        };
+        Grammar::inline_pattern(&mut body, bodies);
+        *pattern = body;
+    }
+
+    // In UTF-8 mode any character is a whole code point rather than a byte,
+    // while in byte mode a negated class matches any single byte that does
+    // not start one of the characters of the class.
+    fn decode(&mut self) {
+        let utf8 = self.utf8;
+        for rule in &mut self.rules {
+            Grammar::decode_pattern(&mut rule.pattern, utf8);
+        }
+        if let Some(ref mut skip) = self.skip {
+            Grammar::decode_pattern(&mut skip.pattern, utf8);
+        }
+    }
+
+    fn decode_pattern(pattern : &mut Pattern, utf8 : bool) {
+        use self::Pattern::*;
+        match *pattern {
+            CharAny if utf8 => {
+                *pattern = CharClass(vec![('\0', Some(::std::char::MAX))], false, false);
+            },
+            CharClass(ref data, true, insensitive) if !utf8 => {
+                let class = CharClass(data.clone(), false, insensitive);
+                *pattern = Sequence(vec![Box::new(Lookahead(false, Box::new(class))), Box::new(CharAny)]);
+            },
+            Choice(ref mut le, ref mut ri) | Recover(ref mut le, ref mut ri, _, _) => {
+                Grammar::decode_pattern(le, utf8);
+                Grammar::decode_pattern(ri, utf8);
+            },
+            Sequence(ref mut data) => {
+                for pattern in data {
+                    Grammar::decode_pattern(pattern, utf8);
+                }
+            },
+            ZeroOrMore(ref mut data) | OneOrMore(ref mut data) | Optional(ref mut data)
+            | Lookahead(_, ref mut data) | Atomic(ref mut data) | Repeat(ref mut data, _, _)
+            | Label(ref mut data, _, _) | Capture(ref mut data, _, _) => {
+                Grammar::decode_pattern(data, utf8);
+            },
+            _ => { }
+        }
+    }
+
     fn name_variables(&mut self) {
         let mut id = 0;
-        for pattern in &mut self.rules {
-            Grammar::name_pattern(pattern, &mut id);
+        for rule in &mut self.rules {
+            Grammar::name_pattern(&mut rule.pattern, &mut id);
+        }
+        if let Some(ref mut skip) = self.skip {
+            Grammar::name_pattern(&mut skip.pattern, &mut id);
         }
     }
 
@@ -202,7 +1480,7 @@ impl Grammar {
                 *name = *id;
                 *id += 1
             },
-            Choice(ref mut le, ref mut ri) => { 
+            Choice(ref mut le, ref mut ri) | Recover(ref mut le, ref mut ri, _, _) => { 
                 Grammar::name_pattern(le, id); 
                 Grammar::name_pattern(ri, id);
             },
@@ -223,14 +1501,29 @@ impl Grammar {
             Lookahead(_, ref mut data) => {
                 Grammar::name_pattern(data, id);
             },
+            Atomic(ref mut data) => {
+                Grammar::name_pattern(data, id);
+            },
+            Repeat(ref mut data, _, _) => {
+                Grammar::name_pattern(data, id);
+            },
+            Label(ref mut data, _, _) => {
+                Grammar::name_pattern(data, id);
+            },
+            Capture(ref mut data, _, _) => {
+                Grammar::name_pattern(data, id);
+            },
             _ => { }
         }
     }
 
     fn label_variables(&mut self, left_recursive_calls : &HashSet<usize>)
     {
-        for pattern in &mut self.rules {
-            Grammar::label_pattern(pattern, left_recursive_calls);
+        for rule in &mut self.rules {
+            Grammar::label_pattern(&mut rule.pattern, left_recursive_calls);
+        }
+        if let Some(ref mut skip) = self.skip {
+            Grammar::label_pattern(&mut skip.pattern, left_recursive_calls);
         }
     }
 
@@ -242,7 +1535,7 @@ impl Grammar {
                     *is_left_recursive = true;
                 }
             },
-            Choice(ref mut le, ref mut ri) => { 
+            Choice(ref mut le, ref mut ri) | Recover(ref mut le, ref mut ri, _, _) => { 
                 Grammar::label_pattern(le, left_recursive_calls); 
                 Grammar::label_pattern(ri, left_recursive_calls);
             },
@@ -263,6 +1556,18 @@ impl Grammar {
             Lookahead(_, ref mut data) => {
                 Grammar::label_pattern(data, left_recursive_calls);
             },
+            Atomic(ref mut data) => {
+                Grammar::label_pattern(data, left_recursive_calls);
+            },
+            Repeat(ref mut data, _, _) => {
+                Grammar::label_pattern(data, left_recursive_calls);
+            },
+            Label(ref mut data, _, _) => {
+                Grammar::label_pattern(data, left_recursive_calls);
+            },
+            Capture(ref mut data, _, _) => {
+                Grammar::label_pattern(data, left_recursive_calls);
+            },
             _ => { }
         }
     }
@@ -270,7 +1575,12 @@ impl Grammar {
     fn discover_left_recursion(&self) -> HashSet<usize> {
         let mut result = HashSet::new();
         let mut right_calls = HashSet::new();
-        self.traverse_pattern(&self.rules[0], &mut vec![], &mut result, &mut right_calls, false);
+        self.traverse_pattern(&self.rules[self.main as usize].pattern, &mut vec![], &mut result, &mut right_calls, false);
+        // Any rule can be an entry point, so cycles out of reach of the main
+        // rule must be found as well.
+        for rule in &self.rules {
+            self.traverse_pattern(&rule.pattern, &mut vec![], &mut result, &mut right_calls, false);
+        }
         result
     }
 
@@ -285,7 +1595,9 @@ impl Grammar {
         use self::Pattern::*;
 
         match pattern {
-            &CharClass(_) | &CharSequence(_) | &CharAny => { consumed = true; },
+            &CharClass(_, _, _) | &CharSequence(_, _) | &CharAny => { consumed = true; },
+            &Cut(_) | &BackReference(_, _, _) | &Reference(_, _, _) | &Apply(_, _, _)
+            | &Class(_, _, _) | &Shorthand(_, _) | &Operators(_, _) | &Operator(_, _, _) => { },
             &Variable(r, _, id, _) => {
                 if left_calls.contains(&id) {
                     
@@ -312,11 +1624,11 @@ impl Grammar {
                     }
                 } else {
                     stack.push((id, consumed));
-                    let tmp = self.traverse_pattern(&self.rules[r as usize], stack, left_calls, right_calls, false);
+                    let tmp = self.traverse_pattern(&self.rules[r as usize].pattern, stack, left_calls, right_calls, false);
                     consumed = consumed || tmp;
                 }
             },
-            &Choice(ref le, ref ri) => {
+            &Choice(ref le, ref ri) | &Recover(ref le, ref ri, _, _) => {
                 let (tmp1, tmp2) = (
                     self.traverse_pattern(le, stack, left_calls, right_calls, consumed),
                     self.traverse_pattern(ri, stack, left_calls, right_calls, consumed));
@@ -325,7 +1637,10 @@ impl Grammar {
             &ZeroOrMore(ref p) | &Optional(ref p) => {
                 self.traverse_pattern(p, stack,  left_calls, right_calls, consumed);
             },
-            &OneOrMore(ref p) => {
+            &Repeat(ref p, 0, _) => {
+                self.traverse_pattern(p, stack,  left_calls, right_calls, consumed);
+            },
+            &OneOrMore(ref p) | &Repeat(ref p, _, _) => {
                 let tmp = self.traverse_pattern(p, stack,  left_calls, right_calls, consumed);
                 consumed = consumed || tmp;
             },
@@ -335,7 +1650,7 @@ impl Grammar {
                     consumed = consumed || tmp;
                 }
             },
-            &Lookahead(_, ref p) => {
+            &Lookahead(_, ref p) | &Atomic(ref p) | &Label(ref p, _, _) | &Capture(ref p, _, _) => {
                 let tmp = self.traverse_pattern(p, stack,  left_calls, right_calls, consumed);
                 consumed = consumed || tmp;
             }
@@ -345,18 +1660,602 @@ impl Grammar {
     }
 }
 
+struct Expansion<'a> {
+    templates : &'a HashMap<String, Rule>,
+    plain : &'a HashSet<String>,
+    instances : &'a mut Vec<Rule>,
+    seen : &'a mut HashMap<(String, Vec<Box<Pattern>>), String>,
+    errors : &'a mut Vec<GrammarError>
+}
+
+impl<'a> Expansion<'a> {
+    fn expand_pattern(&mut self, pattern : &mut Pattern, depth : usize, rule : &str, module : &Option<String>) {
+        use self::Pattern::*;
+        let instance = match *pattern {
+            Reference(ref name, _, span) => {
+                if let Some(template) = self.templates.get(name) {
+                    self.arity_error(template, 0, span, rule, module);
+                }
+                return;
+            },
+            Apply(ref name, ref mut data, span) => {
+                for pattern in data.iter_mut() {
+                    self.expand_pattern(pattern, depth, rule, module);
+                }
+                match self.templates.get(name) {
+                    Some(template) if template.parameters.len() != data.len() => {
+                        self.arity_error(template, data.len(), span, rule, module);
+                        return;
+                    },
+                    Some(template) => self.instantiate(template, data, depth, span, rule, module),
+                    None => {
+                        if self.plain.contains(name) {
+                            self.errors.push(GrammarError {
+                                module: module.clone(),
+                                span,
+                                rule: Some(Grammar::template_name(rule).to_string()),
+                                expected: None,
+                                message: format!("rule `{}` does not take arguments", name)
+                            });
+                        }
+                        name.clone()
+                    }
+                }
+            },
+            Choice(ref mut le, ref mut ri) | Recover(ref mut le, ref mut ri, _, _) => {
+                self.expand_pattern(le, depth, rule, module);
+                self.expand_pattern(ri, depth, rule, module);
+                return;
+            },
+            Sequence(ref mut data) => {
+                for pattern in data {
+                    self.expand_pattern(pattern, depth, rule, module);
+                }
+                return;
+            },
+            ZeroOrMore(ref mut data) | OneOrMore(ref mut data) | Optional(ref mut data)
+            | Lookahead(_, ref mut data) | Atomic(ref mut data) | Repeat(ref mut data, _, _)
+            | Label(ref mut data, _, _) | Capture(ref mut data, _, _) => {
+                self.expand_pattern(data, depth, rule, module);
+                return;
+            },
+            _ => return
+        };
+        if let Apply(_, _, span) = *pattern {
+            *pattern = Reference(instance, -1, span);
+        }
+    }
+
+    // Creates the instance of a template for the given arguments, unless it
+    // already exists, and returns its name.
+    fn instantiate(&mut self,
+        template : &Rule,
+        arguments : &[Box<Pattern>],
+        depth : usize,
+        span : Span,
+        rule : &str,
+        module : &Option<String>)
+        -> String
+    {
+        // Instances are told apart by the shape of their arguments, regardless
+        // of where in the grammar they were written.
+        let key = (template.name.clone(), arguments.iter().map(|x| Box::new(x.without_spans())).collect());
+        if let Some(name) = self.seen.get(&key) {
+            return name.clone();
+        }
+        let name = format!("{}<{}>", template.name, self.seen.len());
+        if depth >= EXPANSION_DEPTH || self.seen.len() >= EXPANSION_COUNT {
+            self.errors.push(GrammarError {
+                module: module.clone(),
+                span,
+                rule: Some(Grammar::template_name(rule).to_string()),
+                expected: None,
+                message: format!("expanding rule `{}` does not terminate", template.name)
+            });
+            return name;
+        }
+        self.seen.insert(key, name.clone());
+
+        let mut pattern = template.pattern.clone();
+        let bindings : HashMap<&String, &Pattern> = template.parameters.iter()
+            .zip(arguments.iter().map(|x| &**x))
+            .collect();
+        Expansion::substitute(&mut pattern, &bindings);
+        self.expand_pattern(&mut pattern, depth + 1, &name, &template.module);
+        self.instances.push(Rule {
+            name: name.clone(),
+            span: template.span,
+            module: template.module.clone(),
+            parameters: vec![],
+            silent: template.silent,
+            inline: template.inline,
+            pattern,
+            comments: vec![]
+        });
+        name
+    }
+
+    fn arity_error(&mut self, template : &Rule, found : usize, span : Span, rule : &str, module : &Option<String>) {
+        self.errors.push(GrammarError {
+            module: module.clone(),
+            span,
+            rule: Some(Grammar::template_name(rule).to_string()),
+            expected: None,
+            message: format!("rule `{}` takes {} argument{}, found {}", template.name,
+                template.parameters.len(), if template.parameters.len() == 1 { "" } else { "s" }, found)
+        });
+    }
+
+    fn substitute(pattern : &mut Pattern, bindings : &HashMap<&String, &Pattern>) {
+        use self::Pattern::*;
+        let argument = match *pattern {
+            Reference(ref name, _, _) => {
+                match bindings.get(name) {
+                    Some(argument) => (*argument).clone(),
+                    None => return
+                }
+            },
+            Apply(_, ref mut data, _) | Sequence(ref mut data) => {
+                for pattern in data {
+                    Expansion::substitute(pattern, bindings);
+                }
+                return;
+            },
+            Choice(ref mut le, ref mut ri) | Recover(ref mut le, ref mut ri, _, _) => {
+                Expansion::substitute(le, bindings);
+                Expansion::substitute(ri, bindings);
+                return;
+            },
+            ZeroOrMore(ref mut data) | OneOrMore(ref mut data) | Optional(ref mut data)
+            | Lookahead(_, ref mut data) | Atomic(ref mut data) | Repeat(ref mut data, _, _)
+            | Label(ref mut data, _, _) | Capture(ref mut data, _, _) => {
+                Expansion::substitute(data, bindings);
+                return;
+            },
+            _ => return
+        };
+        *pattern = argument;
+    }
+}
+
+impl Pattern {
+    /// The same pattern with every span cleared, so that patterns written in
+    /// different places compare equal when they have the same shape.
+    pub fn without_spans(&self) -> Pattern {
+        let mut pattern = self.clone();
+        pattern.erase_spans();
+        pattern
+    }
+
+    fn erase_spans(&mut self) {
+        use self::Pattern::*;
+        match *self {
+            Reference(_, _, ref mut span) | BackReference(_, _, ref mut span) | Cut(ref mut span) => {
+                *span = Span::default();
+            },
+            Apply(_, ref mut data, ref mut span) => {
+                *span = Span::default();
+                for pattern in data {
+                    pattern.erase_spans();
+                }
+            },
+            Sequence(ref mut data) => {
+                for pattern in data {
+                    pattern.erase_spans();
+                }
+            },
+            Choice(ref mut le, ref mut ri) | Recover(ref mut le, ref mut ri, _, _) => {
+                le.erase_spans();
+                ri.erase_spans();
+            },
+            ZeroOrMore(ref mut data) | OneOrMore(ref mut data) | Optional(ref mut data)
+            | Lookahead(_, ref mut data) | Atomic(ref mut data) | Repeat(ref mut data, _, _)
+            | Label(ref mut data, _, _) | Capture(ref mut data, _, _) => {
+                data.erase_spans();
+            },
+            _ => { }
+        }
+    }
+}
+
+// Grammars print as source that reads back into the same tree: a rule per
+// line, or a line per top-level alternative once a rule has several, with
+// parentheses only where the nesting needs them.
+impl fmt::Display for Grammar {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        let names : Vec<&str> = self.rules.iter().map(|x| x.name.as_str()).collect();
+        let mut empty = true;
+        for comment in self.comments.iter().filter(|x| x.line == 0) {
+            writeln!(f, "{}", comment.text)?;
+            empty = false;
+        }
+        for import in &self.imports {
+            let mut path = String::new();
+            Grammar::print_literal(import.path.as_bytes(), &mut path);
+            writeln!(f, "import {} as {}", path, import.name)?;
+            empty = false;
+        }
+        if self.utf8 {
+            writeln!(f, "@utf8")?;
+            empty = false;
+        }
+
+        // Consecutive one-line rules are kept together unless a comment
+        // comes between them.
+        let mut single = false;
+        for rule in self.skip.iter().chain(self.rules.iter()) {
+            let text = Grammar::print_rule(rule, &names);
+            let leading : Vec<&Comment> = rule.comments.iter().filter(|x| x.line == 0 && !x.trailing).collect();
+            let grouped = single && leading.is_empty() && !text.contains('\n');
+            if !empty && !grouped {
+                writeln!(f)?;
+            }
+            for comment in leading {
+                writeln!(f, "{}", comment.text)?;
+            }
+            writeln!(f, "{}", text)?;
+            single = !text.contains('\n');
+            empty = false;
+        }
+
+        let mut footer = self.comments.iter().filter(|x| x.line != 0).peekable();
+        if !empty && footer.peek().is_some() {
+            writeln!(f)?;
+        }
+        for comment in footer {
+            writeln!(f, "{}", comment.text)?;
+        }
+        Ok(())
+    }
+}
+
+impl Grammar {
+    fn print_rule(rule : &Rule, names : &[&str]) -> String {
+        let mut out = String::new();
+        let (atomic, pattern) = match rule.pattern {
+            Pattern::Atomic(ref data) => (true, &**data),
+            ref pattern => (false, pattern)
+        };
+        for &(modifier, set) in &[("@silent ", rule.silent), ("@inline ", rule.inline), ("@atomic ", atomic)] {
+            if set {
+                out.push_str(modifier);
+            }
+        }
+        // An operator table has a line per level rather than per alternative.
+        let mut alternatives = vec![];
+        if let Pattern::Operators(ref operand, ref levels) = *pattern {
+            out.push_str("@operators(");
+            Grammar::print_pattern(operand, 0, names, &mut out);
+            out.push_str(") ");
+            for &(fixity, ref operators) in levels {
+                let mut level = String::new();
+                Grammar::print_level(fixity, operators, names, &mut level);
+                alternatives.push(level);
+            }
+        } else {
+            let mut patterns = vec![];
+            Grammar::print_alternatives(pattern, String::new(), true, &mut patterns);
+            for (separator, pattern) in patterns {
+                let mut alternative = separator;
+                Grammar::print_pattern(pattern, 2, names, &mut alternative);
+                alternatives.push(alternative);
+            }
+        }
+        out.push_str(&rule.name);
+        if !rule.parameters.is_empty() {
+            out.push_str(&format!("<{}>", rule.parameters.join(", ")));
+        }
+
+        let last = alternatives.len();
+        let trailing = |line : usize| -> String {
+            rule.comments.iter()
+                .filter(|x| x.trailing && (x.line == line || (line > last && x.line > last)))
+                .map(|x| format!(" {}", x.text))
+                .collect()
+        };
+        let multiline = last > 1 || rule.comments.iter()
+            .any(|x| (x.line == 0 && x.trailing) || (x.line > 0 && !(x.line > last && x.trailing)));
+
+        if !multiline {
+            out.push_str(" { ");
+            out.push_str(&alternatives[0]);
+            out.push_str(" }");
+        } else {
+            out.push_str(" {");
+            out.push_str(&trailing(0));
+            out.push('\n');
+            for (k, alternative) in alternatives.iter().enumerate() {
+                for comment in rule.comments.iter().filter(|x| x.line == k + 1 && !x.trailing) {
+                    out.push_str(&format!("    {}\n", comment.text));
+                }
+                out.push_str("    ");
+                out.push_str(alternative);
+                out.push_str(&trailing(k + 1));
+                out.push('\n');
+            }
+            for comment in rule.comments.iter().filter(|x| x.line > last && !x.trailing) {
+                out.push_str(&format!("    {}\n", comment.text));
+            }
+            out.push('}');
+        }
+        out.push_str(&trailing(last + 1));
+        out
+    }
+
+    // Splits the body of a rule into its top-level alternatives, each with
+    // the separator that goes before it.
+    fn print_alternatives<'a>(pattern : &'a Pattern,
+        separator : String,
+        recover : bool,
+        out : &mut Vec<(String, &'a Pattern)>)
+    {
+        match *pattern {
+            Pattern::Recover(ref le, ref ri, ref labels, _) if recover => {
+                Grammar::print_alternatives(le, separator, true, out);
+                Grammar::print_alternatives(ri, format!("/{{{}}} ", labels.join(", ")), false, out);
+            },
+            Pattern::Choice(ref le, ref ri) => {
+                out.push((separator, le));
+                Grammar::print_alternatives(ri, "/ ".to_string(), false, out);
+            },
+            _ => out.push((separator, pattern))
+        }
+    }
+
+    // Prints a level of an operator table, such as `@left '+' / '-'`.
+    fn print_level(fixity : Fixity, operators : &[Box<Pattern>], names : &[&str], out : &mut String) {
+        out.push_str(&format!("@{}", Grammar::fixity_name(fixity)));
+        for (k, operator) in operators.iter().enumerate() {
+            out.push_str(if k > 0 { " / " } else { " " });
+            Grammar::print_pattern(operator, 2, names, out);
+        }
+    }
+
+    fn fixity_name(fixity : Fixity) -> &'static str {
+        match fixity {
+            Fixity::Left => "left",
+            Fixity::Right => "right",
+            Fixity::NonAssoc => "nonassoc",
+            Fixity::Prefix => "prefix",
+            Fixity::Postfix => "postfix"
+        }
+    }
+
+    // Prints a pattern where the surrounding syntax binds at `level`, from 0
+    // for a whole expression to 6 for a primary.
+    fn print_pattern(pattern : &Pattern, level : usize, names : &[&str], out : &mut String) {
+        use self::Pattern::*;
+        let own = match *pattern {
+            Recover(..) | Operators(..) => 0,
+            Choice(..) => 1,
+            Sequence(..) | Operator(..) => 2,
+            Lookahead(..) | Capture(..) => 3,
+            Label(..) => 4,
+            ZeroOrMore(_) | OneOrMore(_) | Optional(_) | Repeat(..) => 5,
+            _ => 6
+        };
+        if own < level {
+            out.push('(');
+            Grammar::print_pattern(pattern, 0, names, out);
+            out.push(')');
+            return;
+        }
+
+        match *pattern {
+            CharClass(ref ranges, negated, insensitive) => {
+                out.push('[');
+                if negated {
+                    out.push('^');
+                }
+                for &(first, last) in ranges {
+                    Grammar::print_char(first, true, out);
+                    if let Some(last) = last {
+                        out.push('-');
+                        Grammar::print_char(last, true, out);
+                    }
+                }
+                out.push(']');
+                if insensitive {
+                    out.push('i');
+                }
+            },
+            Class(ref items, negated, insensitive) => {
+                out.push('[');
+                if negated {
+                    out.push('^');
+                }
+                for item in items {
+                    match *item {
+                        ClassItem::Range(first, last) => {
+                            Grammar::print_char(first, true, out);
+                            if let Some(last) = last {
+                                out.push('-');
+                                Grammar::print_char(last, true, out);
+                            }
+                        },
+                        ClassItem::Named(ref text, _) => out.push_str(text)
+                    }
+                }
+                out.push(']');
+                if insensitive {
+                    out.push('i');
+                }
+            },
+            Shorthand(ref text, _) => out.push_str(text),
+            CharSequence(ref bytes, insensitive) => {
+                Grammar::print_literal(bytes, out);
+                if insensitive {
+                    out.push('i');
+                }
+            },
+            CharAny => out.push('.'),
+            Variable(id, precedence, _, _) => {
+                out.push_str(names[id as usize]);
+                if precedence != -1 {
+                    out.push_str(&format!(":{}", precedence));
+                }
+            },
+            Choice(ref le, ref ri) => {
+                Grammar::print_pattern(le, 2, names, out);
+                out.push_str(" / ");
+                Grammar::print_pattern(ri, 1, names, out);
+            },
+            ZeroOrMore(ref data) => {
+                Grammar::print_pattern(data, 6, names, out);
+                out.push('*');
+            },
+            OneOrMore(ref data) => {
+                Grammar::print_pattern(data, 6, names, out);
+                out.push('+');
+            },
+            Optional(ref data) => {
+                Grammar::print_pattern(data, 6, names, out);
+                out.push('?');
+            },
+            Repeat(ref data, min, max) => {
+                Grammar::print_pattern(data, 6, names, out);
+                match max {
+                    Some(max) if max == min => out.push_str(&format!("{{{}}}", min)),
+                    Some(max) => out.push_str(&format!("{{{},{}}}", min, max)),
+                    None => out.push_str(&format!("{{{},}}", min))
+                }
+            },
+            Sequence(ref data) => {
+                for (k, pattern) in data.iter().enumerate() {
+                    if k > 0 {
+                        out.push(' ');
+                    }
+                    Grammar::print_pattern(pattern, 3, names, out);
+                }
+            },
+            // A table is only ever the body of a rule, which `print_rule`
+            // prints along with its operand.
+            Operators(_, ref levels) => {
+                for (k, &(fixity, ref operators)) in levels.iter().enumerate() {
+                    if k > 0 {
+                        out.push(' ');
+                    }
+                    Grammar::print_level(fixity, operators, names, out);
+                }
+            },
+            Operator(fixity, level, ref data) => {
+                out.push_str(&format!("@{}({}) ", Grammar::fixity_name(fixity), level));
+                for (k, pattern) in data.iter().enumerate() {
+                    if k > 0 {
+                        out.push(' ');
+                    }
+                    Grammar::print_pattern(pattern, 3, names, out);
+                }
+            },
+            Lookahead(positive, ref data) => {
+                out.push(if positive { '&' } else { '!' });
+                Grammar::print_pattern(data, 4, names, out);
+            },
+            Atomic(ref data) => {
+                out.push_str("@atomic(");
+                Grammar::print_pattern(data, 0, names, out);
+                out.push(')');
+            },
+            Cut(_) => out.push('~'),
+            Label(ref data, ref name, _) => {
+                Grammar::print_pattern(data, 5, names, out);
+                out.push('^');
+                out.push_str(name);
+            },
+            Recover(ref le, ref ri, ref labels, _) => {
+                Grammar::print_pattern(le, 0, names, out);
+                out.push_str(&format!(" /{{{}}} ", labels.join(", ")));
+                Grammar::print_pattern(ri, 1, names, out);
+            },
+            Capture(ref data, ref name, _) => {
+                out.push_str(&format!("${}:", name));
+                Grammar::print_pattern(data, 4, names, out);
+            },
+            BackReference(ref name, _, _) => out.push_str(&format!("${}", name)),
+            Reference(ref name, precedence, _) => {
+                out.push_str(name);
+                if precedence != -1 {
+                    out.push_str(&format!(":{}", precedence));
+                }
+            },
+            Apply(ref name, ref arguments, _) => {
+                out.push_str(name);
+                out.push('<');
+                for (k, argument) in arguments.iter().enumerate() {
+                    if k > 0 {
+                        out.push_str(", ");
+                    }
+                    Grammar::print_pattern(argument, 0, names, out);
+                }
+                out.push('>');
+            }
+        }
+    }
+
+    // Literals keep their text where it is valid UTF-8 and fall back to byte
+    // escapes elsewhere.
+    fn print_literal(bytes : &[u8], out : &mut String) {
+        out.push('\'');
+        let mut rest = bytes;
+        while !rest.is_empty() {
+            let valid = match ::std::str::from_utf8(rest) {
+                Ok(text) => text,
+                Err(error) => ::std::str::from_utf8(&rest[..error.valid_up_to()]).unwrap()
+            };
+            for x in valid.chars() {
+                Grammar::print_char(x, false, out);
+            }
+            rest = &rest[valid.len()..];
+            if let Some((&byte, remaining)) = rest.split_first() {
+                out.push_str(&format!("\\x{:02x}", byte));
+                rest = remaining;
+            }
+        }
+        out.push('\'');
+    }
+
+    fn print_char(x : char, in_bracket : bool, out : &mut String) {
+        match x {
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            '\n' => out.push_str("\\n"),
+            '\0' => out.push_str("\\0"),
+            '\\' | '\'' => {
+                out.push('\\');
+                out.push(x);
+            },
+            '-' | '^' | '[' | ']' if in_bracket => {
+                out.push('\\');
+                out.push(x);
+            },
+            ' ' => out.push(' '),
+            _ if x.is_control() || x.is_whitespace() => out.push_str(&format!("\\u{{{:x}}}", x as u32)),
+            _ => out.push(x)
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::marker::PhantomData;
 
-    fn execute_test(grammar : &mut Grammar, subjects : &Vec<&str>, expected : &Vec<bool>, rule_names : Vec<String>) {
+    fn rule(name : &str, pattern : Pattern) -> Rule {
+        Rule { name: name.to_string(), span: Span::default(), module: None, parameters: vec![],
+            silent: false, inline: false, pattern, comments: vec![] }
+    }
+
+    fn execute_test(grammar : &mut Grammar, subjects : &[&str], expected : &[bool]) {
         let program = grammar.compile();
+        let rule_names = grammar.rule_names();
         let jump_table = machine::Machine::<String>::get_jump_table(&program);
         let mut machine = machine::Machine::<String> {
             program: program,
             rule_names: rule_names,
-            skip: vec![],
+            entries: grammar.entries(),
+            labels: grammar.labels.clone(),
+            capture_names: grammar.captures.clone(),
+            skip: None,
             skip_on: false,
             jump_table: jump_table,
             marker: PhantomData
@@ -380,14 +2279,14 @@ mod tests {
             any { . }
         */
         let char_class = Pattern::CharClass(vec![
-            ('a' as u8, Some('z' as u8)),
-            ('A' as u8, Some('A' as u8))
-        ]);
+            ('a', Some('z')),
+            ('A', Some('A'))
+        ], false, false);
         let char_seq = Pattern::CharSequence(vec![
             'a' as u8,
             'b' as u8,
             'c' as u8
-        ]);
+        ], false);
         let main = Pattern::Sequence(vec![
             Box::new(Pattern::Variable(1, 1, 0, false)),
             Box::new(Pattern::Variable(2, 1, 0, false)),
@@ -396,25 +2295,30 @@ mod tests {
 
         let mut grammar = Grammar {
             rules: vec![
-                main,
-                Pattern::CharAny,
-                char_class,
-                char_seq
+                rule("main", main),
+                rule("any", Pattern::CharAny),
+                rule("char_class", char_class),
+                rule("char_seq", char_seq)
             ],
-            main: 0
+            main: 0,
+            skip: None,
+            utf8: false,
+            imports: vec![],
+            labels: vec![],
+            captures: vec![],
+            comments: vec![]
         };
         let subjects = vec!["azabc", "Bkabc", "AAabc", "aqd", "xyz"];
         let expected = vec![true, true, true, false, false];
-        let rule_names = vec!["main".to_string(), "any".to_string(), "char_class".to_string(), "char_seq".to_string()];
-        execute_test(&mut grammar, &subjects, &expected, rule_names);
+        execute_test(&mut grammar, &subjects, &expected);
     }
 
     #[test]
     fn simple_lookahead_grammar() { // main { !a;. / &a;. }
         let ambersand = Pattern::Lookahead(true,
-            Box::new(Pattern::CharSequence(vec!['a' as u8])));
+            Box::new(Pattern::CharSequence(vec![b'a'], false)));
         let not = Pattern::Lookahead(false,
-            Box::new(Pattern::CharSequence(vec!['a' as u8])));
+            Box::new(Pattern::CharSequence(vec![b'a'], false)));
         let main = Pattern::Choice(
             Box::new(Pattern::Sequence(vec![
                 Box::new(not),
@@ -428,37 +2332,47 @@ mod tests {
 
         let mut grammar = Grammar {
             rules: vec![
-                main
+                rule("main", main)
             ],
-            main: 0
+            main: 0,
+            skip: None,
+            utf8: false,
+            imports: vec![],
+            labels: vec![],
+            captures: vec![],
+            comments: vec![]
         };
         let subjects = vec!["b", "a", "z", "aa", ""];
         let expected = vec![true, true, true, false, false];
-        let rule_names = vec!["main".to_string()];
-        execute_test(&mut grammar, &subjects, &expected, rule_names);
+        execute_test(&mut grammar, &subjects, &expected);
     }
 
     #[test]
     fn simple_iteration_grammar() { // main { a+ / b* }
         let main = Pattern::Choice(
             Box::new(Pattern::OneOrMore(
-                Box::new(Pattern::CharSequence(vec!['a' as u8]))
+                Box::new(Pattern::CharSequence(vec![b'a'], false))
             )),
             Box::new(Pattern::ZeroOrMore(
-                Box::new(Pattern::CharSequence(vec!['b' as u8]))
+                Box::new(Pattern::CharSequence(vec![b'b'], false))
             ))
         );
 
         let mut grammar = Grammar {
             rules: vec![
-                main
+                rule("main", main)
             ],
-            main: 0
+            main: 0,
+            skip: None,
+            utf8: false,
+            imports: vec![],
+            labels: vec![],
+            captures: vec![],
+            comments: vec![]
         };
         let subjects = vec!["a", "aaaa", "", "b", "bbbbb", "c"];
         let expected = vec![true, true, true, true, true, false];
-        let rule_names = vec!["main".to_string()];
-        execute_test(&mut grammar, &subjects, &expected, rule_names);
+        execute_test(&mut grammar, &subjects, &expected);
     }
 
     #[test]
@@ -468,22 +2382,27 @@ mod tests {
             a { a+ }
         */
         let a = Pattern::OneOrMore(
-            Box::new(Pattern::CharSequence(vec!['a' as u8])));
+            Box::new(Pattern::CharSequence(vec![b'a'], false)));
         let main = Pattern::Sequence(vec![
             Box::new(Pattern::Optional(Box::new(Pattern::Variable(1, 1, 0, false)))),
-            Box::new(Pattern::CharSequence(vec!['b' as u8]))
+            Box::new(Pattern::CharSequence(vec![b'b'], false))
         ]);
 
         let mut grammar = Grammar {
             rules: vec![
-                main,
-                a
+                rule("main", main),
+                rule("a", a)
             ],
-            main: 0
+            main: 0,
+            skip: None,
+            utf8: false,
+            imports: vec![],
+            labels: vec![],
+            captures: vec![],
+            comments: vec![]
         };
         let subjects = vec!["b", "ab", "aaaaab", "", "bb"];
         let expected = vec![true, true, true, false, false];
-        let rule_names = vec!["main".to_string(), "a".to_string()];
-        execute_test(&mut grammar, &subjects, &expected, rule_names);
+        execute_test(&mut grammar, &subjects, &expected);
     }
 }
\ No newline at end of file
@@ -0,0 +1,352 @@
+use std::hash::Hash;
+use std::str::FromStr;
+use ast;
+use machine::Machine;
+use parser::{GrammarError, Span};
+
+/// A grammar put together in code that has been checked in the same way as
+/// one read from text, ready to be compiled into a machine.
+#[derive(Debug)]
+pub struct Grammar {
+    grammar : ast::Grammar
+}
+
+/// Collects the rules of a grammar, see `Grammar::builder`.
+#[derive(Debug)]
+pub struct GrammarBuilder {
+    grammar : ast::Grammar,
+    errors : Vec<GrammarError>
+}
+
+/// A pattern with the same meaning as its counterpart in the grammar syntax.
+/// A string converts into the literal that matches it, so that the `seq!`
+/// and `choice!` macros can mix strings and patterns.
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    pattern : ast::Pattern
+}
+
+/// Matches each pattern in turn, as in `a b c`.
+#[macro_export]
+macro_rules! seq {
+    ($($x:expr),* $(,)*) => {
+        $crate::Pattern::sequence(vec![$($crate::Pattern::from($x)),*])
+    };
+}
+
+/// Matches the first pattern that matches, as in `a / b / c`.
+#[macro_export]
+macro_rules! choice {
+    ($($x:expr),* $(,)*) => {
+        $crate::Pattern::choice(vec![$($crate::Pattern::from($x)),*])
+    };
+}
+
+impl Grammar {
+    pub fn builder() -> GrammarBuilder {
+        GrammarBuilder {
+            grammar: ast::Grammar { rules: vec![], main: 0, skip: None, utf8: false, imports: vec![], labels: vec![], captures: vec![], comments: vec![] },
+            errors: vec![]
+        }
+    }
+
+    pub fn machine<T>(self) -> Machine<T>
+        where T : Eq + Hash + FromStr
+    {
+        Machine::from_grammar(self.grammar)
+    }
+}
+
+impl GrammarBuilder {
+    pub fn rule<P : Into<Pattern>>(self, name : &str, pattern : P) -> GrammarBuilder {
+        self.add(name, &[], pattern.into(), false, false)
+    }
+
+    /// Adds a rule that is matched without recording its span, like
+    /// `@silent`.
+    pub fn silent_rule<P : Into<Pattern>>(self, name : &str, pattern : P) -> GrammarBuilder {
+        self.add(name, &[], pattern.into(), true, false)
+    }
+
+    /// Adds a rule that is spliced into the rules referring to it, like
+    /// `@inline`.
+    pub fn inline_rule<P : Into<Pattern>>(self, name : &str, pattern : P) -> GrammarBuilder {
+        self.add(name, &[], pattern.into(), false, true)
+    }
+
+    /// Adds a rule with parameters, which is expanded for each `Pattern::apply`
+    /// of it.
+    pub fn template<P : Into<Pattern>>(self, name : &str, parameters : &[&str], pattern : P) -> GrammarBuilder {
+        self.add(name, parameters, pattern.into(), false, false)
+    }
+
+    pub fn skip<P : Into<Pattern>>(mut self, pattern : P) -> GrammarBuilder {
+        if self.grammar.skip.is_some() {
+            self.errors.push(GrammarBuilder::error("skip", "the skip rule is defined more than once".to_string()));
+        }
+        self.grammar.skip = Some(GrammarBuilder::new_rule("skip", &[], pattern.into().pattern, false, false));
+        self
+    }
+
+    /// Makes `Pattern::any` match a whole code point, like `@utf8`.
+    pub fn utf8(mut self) -> GrammarBuilder {
+        self.grammar.utf8 = true;
+        self
+    }
+
+    /// Checks the grammar, reporting the same errors as reading it from text
+    /// would. The errors have no position, but name the rule they are in.
+    pub fn build(mut self) -> Result<Grammar, Vec<GrammarError>> {
+        for rule in &self.grammar.rules {
+            let names = vec![&rule.name].into_iter().chain(rule.parameters.iter());
+            for name in names.filter(|x| !is_name(x)) {
+                self.errors.push(GrammarBuilder::error(&rule.name, format!("`{}` is not a valid rule name", name)));
+            }
+        }
+        for rule in self.grammar.rules.iter().chain(self.grammar.skip.iter()) {
+            GrammarBuilder::check_pattern(&rule.pattern, &rule.name, &mut self.errors);
+        }
+        if !self.errors.is_empty() {
+            return Err(self.errors);
+        }
+        self.grammar.expand()?;
+        self.grammar.resolve()?;
+        Ok(Grammar { grammar: self.grammar })
+    }
+
+    fn add(mut self, name : &str, parameters : &[&str], pattern : Pattern, silent : bool, inline : bool) -> GrammarBuilder {
+        self.grammar.rules.push(GrammarBuilder::new_rule(name, parameters, pattern.pattern, silent, inline));
+        self
+    }
+
+    fn new_rule(name : &str, parameters : &[&str], pattern : ast::Pattern, silent : bool, inline : bool) -> ast::Rule {
+        ast::Rule {
+            name: name.to_string(),
+            span: Span::default(),
+            module: None,
+            parameters: parameters.iter().map(|x| x.to_string()).collect(),
+            silent,
+            inline,
+            pattern,
+            comments: vec![]
+        }
+    }
+
+    fn error(rule : &str, message : String) -> GrammarError {
+        GrammarError {
+            module: None,
+            span: Span::default(),
+            rule: Some(rule.to_string()),
+            expected: None,
+            message
+        }
+    }
+
+    // Finds the names and bounds that the grammar syntax could not express.
+    fn check_pattern(pattern : &ast::Pattern, rule : &str, errors : &mut Vec<GrammarError>) {
+        use ast::Pattern::*;
+        match *pattern {
+            Reference(ref name, _, _) | Apply(ref name, _, _) if !is_name(name) => {
+                errors.push(GrammarBuilder::error(rule, format!("`{}` is not a valid rule name", name)));
+            },
+            Label(_, ref name, _) | Capture(_, ref name, _) | BackReference(ref name, _, _) if !is_name(name) => {
+                errors.push(GrammarBuilder::error(rule, format!("`{}` is not a valid name", name)));
+            },
+            Recover(_, _, ref labels, _) => {
+                for label in labels.iter().filter(|x| !is_name(x)) {
+                    errors.push(GrammarBuilder::error(rule, format!("`{}` is not a valid name", label)));
+                }
+            },
+            CharClass(ref ranges, _, _) => {
+                for &(first, last) in ranges {
+                    if let Some(last) = last.filter(|&x| x < first) {
+                        errors.push(GrammarBuilder::error(rule,
+                            format!("range `{}-{}` is reversed", first.escape_default(), last.escape_default())));
+                    }
+                }
+            },
+            Reference(ref name, precedence, _) if precedence < -1 => {
+                errors.push(GrammarBuilder::error(rule,
+                    format!("the precedence of `{}` is larger than {}", name, i32::MAX)));
+            },
+            Repeat(_, min, Some(max)) if max < min => {
+                errors.push(GrammarBuilder::error(rule, format!("the repetition maximum {} is less than the minimum {}", max, min)));
+            },
+            _ => { }
+        }
+        match *pattern {
+            Apply(_, ref data, _) | Sequence(ref data) => {
+                for pattern in data {
+                    GrammarBuilder::check_pattern(pattern, rule, errors);
+                }
+            },
+            Choice(ref le, ref ri) | Recover(ref le, ref ri, _, _) => {
+                GrammarBuilder::check_pattern(le, rule, errors);
+                GrammarBuilder::check_pattern(ri, rule, errors);
+            },
+            ZeroOrMore(ref data) | OneOrMore(ref data) | Optional(ref data)
+            | Lookahead(_, ref data) | Atomic(ref data) | Repeat(ref data, _, _)
+            | Label(ref data, _, _) | Capture(ref data, _, _) => {
+                GrammarBuilder::check_pattern(data, rule, errors);
+            },
+            _ => { }
+        }
+    }
+}
+
+// Names are spelled as the tokenizer reads them.
+fn is_name(name : &str) -> bool {
+    !name.is_empty() && name != "skip"
+        && !name.chars().all(|x| x.is_ascii_digit())
+        && name.chars().all(|x| x.is_alphanumeric() || x == '_')
+}
+
+impl<'a> From<&'a str> for Pattern {
+    fn from(text : &'a str) -> Pattern {
+        Pattern::literal(text)
+    }
+}
+
+impl Pattern {
+    pub fn literal(text : &str) -> Pattern {
+        Pattern::bytes(text.as_bytes())
+    }
+
+    pub fn bytes(bytes : &[u8]) -> Pattern {
+        Pattern { pattern: ast::Pattern::CharSequence(bytes.to_vec(), false) }
+    }
+
+    /// Matches a character in any of the inclusive ranges, like `[a-z_]`
+    /// for `&[('a', 'z'), ('_', '_')]`.
+    pub fn class(ranges : &[(char, char)]) -> Pattern {
+        let ranges = ranges.iter()
+            .map(|&(first, last)| (first, if first == last { None } else { Some(last) }))
+            .collect();
+        Pattern { pattern: ast::Pattern::CharClass(ranges, false, false) }
+    }
+
+    pub fn any() -> Pattern {
+        Pattern { pattern: ast::Pattern::CharAny }
+    }
+
+    pub fn rule(name : &str) -> Pattern {
+        Pattern { pattern: ast::Pattern::Reference(name.to_string(), -1, Span::default()) }
+    }
+
+    /// Refers to a rule at a precedence, like `expr:2`. Precedences larger
+    /// than `i32::MAX` are reported when the grammar is built.
+    pub fn rule_at(name : &str, precedence : u32) -> Pattern {
+        let precedence = if precedence > i32::MAX as u32 { i32::MIN } else { precedence as i32 };
+        Pattern { pattern: ast::Pattern::Reference(name.to_string(), precedence, Span::default()) }
+    }
+
+    /// Applies a template to arguments, like `list<item, ','>`.
+    pub fn apply(name : &str, arguments : Vec<Pattern>) -> Pattern {
+        let arguments = arguments.into_iter().map(|x| Box::new(x.pattern)).collect();
+        Pattern { pattern: ast::Pattern::Apply(name.to_string(), arguments, Span::default()) }
+    }
+
+    /// Matches each pattern in turn. An empty sequence matches the empty
+    /// string.
+    pub fn sequence(patterns : Vec<Pattern>) -> Pattern {
+        let mut patterns : Vec<Box<ast::Pattern>> = patterns.into_iter().map(|x| Box::new(x.pattern)).collect();
+        match patterns.len() {
+            0 => Pattern::literal(""),
+            1 => Pattern { pattern: *patterns.pop().unwrap() },
+            _ => Pattern { pattern: ast::Pattern::Sequence(patterns) }
+        }
+    }
+
+    /// Matches the first pattern that matches. An empty choice never
+    /// matches.
+    pub fn choice(mut patterns : Vec<Pattern>) -> Pattern {
+        let mut result = match patterns.pop() {
+            Some(x) => x.pattern,
+            None => return Pattern::literal("").not_followed_by()
+        };
+        while let Some(x) = patterns.pop() {
+            result = ast::Pattern::Choice(Box::new(x.pattern), Box::new(result));
+        }
+        Pattern { pattern: result }
+    }
+
+    /// Commits to the alternative the cut is in, like `~`.
+    pub fn cut() -> Pattern {
+        Pattern { pattern: ast::Pattern::Cut(Span::default()) }
+    }
+
+    /// Matches the text last captured under the name, like `$name`. The name
+    /// must be captured by the same rule.
+    pub fn back_reference(name : &str) -> Pattern {
+        Pattern { pattern: ast::Pattern::BackReference(name.to_string(), 0, Span::default()) }
+    }
+
+    pub fn zero_or_more(self) -> Pattern {
+        Pattern { pattern: ast::Pattern::ZeroOrMore(Box::new(self.pattern)) }
+    }
+
+    pub fn one_or_more(self) -> Pattern {
+        Pattern { pattern: ast::Pattern::OneOrMore(Box::new(self.pattern)) }
+    }
+
+    pub fn optional(self) -> Pattern {
+        Pattern { pattern: ast::Pattern::Optional(Box::new(self.pattern)) }
+    }
+
+    /// Matches at least `min` times and at most `max` times, like `{2,5}`.
+    pub fn repeat(self, min : u32, max : Option<u32>) -> Pattern {
+        Pattern { pattern: ast::Pattern::Repeat(Box::new(self.pattern), min, max) }
+    }
+
+    /// Succeeds without consuming input if the pattern matches, like `&`.
+    pub fn followed_by(self) -> Pattern {
+        Pattern { pattern: ast::Pattern::Lookahead(true, Box::new(self.pattern)) }
+    }
+
+    /// Succeeds without consuming input unless the pattern matches, like `!`.
+    pub fn not_followed_by(self) -> Pattern {
+        Pattern { pattern: ast::Pattern::Lookahead(false, Box::new(self.pattern)) }
+    }
+
+    pub fn atomic(self) -> Pattern {
+        Pattern { pattern: ast::Pattern::Atomic(Box::new(self.pattern)) }
+    }
+
+    /// Makes a literal or a class case-insensitive, like a trailing `i`.
+    /// Other patterns are left as they are.
+    pub fn insensitive(self) -> Pattern {
+        let pattern = match self.pattern {
+            ast::Pattern::CharSequence(bytes, _) => ast::Pattern::CharSequence(bytes, true),
+            ast::Pattern::CharClass(ranges, negated, _) => ast::Pattern::CharClass(ranges, negated, true),
+            pattern => pattern
+        };
+        Pattern { pattern }
+    }
+
+    /// Makes a class match the characters outside its ranges, like `[^...]`.
+    /// Other patterns are left as they are.
+    pub fn negated(self) -> Pattern {
+        let pattern = match self.pattern {
+            ast::Pattern::CharClass(ranges, _, insensitive) => ast::Pattern::CharClass(ranges, true, insensitive),
+            pattern => pattern
+        };
+        Pattern { pattern }
+    }
+
+    /// Throws the label if the pattern fails, like `^label`.
+    pub fn label(self, name : &str) -> Pattern {
+        Pattern { pattern: ast::Pattern::Label(Box::new(self.pattern), name.to_string(), 0) }
+    }
+
+    /// Matches the recovery pattern when one of the labels is thrown, like
+    /// `/{labels}`.
+    pub fn recover(self, labels : &[&str], recovery : Pattern) -> Pattern {
+        let labels = labels.iter().map(|x| x.to_string()).collect();
+        Pattern { pattern: ast::Pattern::Recover(Box::new(self.pattern), Box::new(recovery.pattern), labels, 0) }
+    }
+
+    /// Captures the text matched under the name, like `$name:`.
+    pub fn capture(self, name : &str) -> Pattern {
+        Pattern { pattern: ast::Pattern::Capture(Box::new(self.pattern), name.to_string(), 0) }
+    }
+}
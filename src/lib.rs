@@ -2,8 +2,21 @@
 mod ast;
 mod parser;
 mod machine;
+mod builder;
+mod unicode;
 
 pub type Machine<T> = machine::Machine<T>;
+pub type GrammarError = parser::GrammarError;
+pub type Span = parser::Span;
+pub type Grammar = builder::Grammar;
+pub type GrammarBuilder = builder::GrammarBuilder;
+pub type Pattern = builder::Pattern;
+
+/// Prints grammar source in its canonical layout, with one alternative per
+/// line and the comments kept in place.
+pub fn format(grammar : &str) -> Result<String, GrammarError> {
+    parser::format(grammar)
+}
 
 #[cfg(test)]
 mod tests {
@@ -1,17 +1,20 @@
 use std::str::FromStr;
 use std::path::Path;
-use std::fs::File;
-use std::io::prelude::*;
 use std::hash::Hash;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::marker::PhantomData;
 use parser;
+use ast;
 
 #[derive(Debug, Copy, Clone)]
 enum StackFrame {
-    Return(isize),
-    Backtrack(isize, usize),
-    PrecedenceBacktrack(isize, isize, usize, Option<usize>, isize, bool, bool)
+    Return(isize, usize),
+    Backtrack(isize, usize, bool, usize),
+    PrecedenceBacktrack(isize, isize, usize, Option<usize>, isize, bool, bool, bool, usize),
+    Skip(bool),
+    Counter(usize),
+    Recovery(isize, bool, u64, usize),
+    Mark(usize)
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -20,6 +23,7 @@ pub enum Instruction {
     TestChar(u8, isize),
     Any,
     TestAny(usize, isize),
+    CharFold(u8),
     CharRange(u8, u8),
     CharRangeLink(u8, u8, isize),
     Choice(isize),
@@ -31,11 +35,24 @@ pub enum Instruction {
     BackCommit(isize),
     PartialCommit(isize),
     PushPos(usize),
+    PushSilent,
     SavePos,
     Fail,
     FailTwice,
     Stop,
-    ToggleSkip
+    Skip,
+    ToggleSkip,
+    PushSkip(bool),
+    PopSkip,
+    PushCounter,
+    TestCounter(usize, isize),
+    IncrementCounter,
+    PopCounter,
+    Throw(usize),
+    Recover(isize, u64),
+    Mark,
+    Capture(usize),
+    BackReference(usize)
 }
 
 pub struct Machine<T> 
@@ -43,15 +60,24 @@ pub struct Machine<T>
 {
     pub program: Vec<Instruction>,
     pub rule_names: Vec<String>,
-    pub skip : Vec<(u8, u8)>,
+    pub entries : HashMap<String, usize>,
+    pub labels : Vec<String>,
+    pub capture_names : Vec<String>,
+    pub skip : Option<usize>,
     pub skip_on : bool,
     pub jump_table : Vec<isize>,
     pub marker : PhantomData<T>
 }
 
+/// The rules matched by a successful run, each with the start and end of the
+/// text it matched.
+pub type Matches<T> = Result<Vec<(T, usize, usize)>, Error<<T as FromStr>::Err>>;
+
 #[derive(Debug)]
 pub enum Error<T> {
     MarkerError(T),
+    RuleError(String),
+    LabelError(String, usize),
     ParserError(usize),
     MachineError(usize)
 }
@@ -59,43 +85,100 @@ pub enum Error<T> {
 impl<T> Machine<T>
     where T : Eq + Hash + FromStr
 {
-    pub fn skip_parser(&mut self, x : u8) -> bool {
-        let mut result = false;
-        for t in &self.skip {
-            result |= x >= t.0 && x <= t.1;
+    pub fn skip_parser(&self, input : &[u8], i : usize) -> usize {
+        match self.skip {
+            Some(entry) => {
+                match self.run(input, entry as isize, i, false, None) {
+                    Ok((false, j)) => j,
+                    _ => i
+                }
+            },
+            None => i
         }
+    }
 
-        result
+    pub fn execute(&mut self, input : Vec<u8>) -> Matches<T> {
+        self.execute_from(0, input)
+    }
+
+    /// Matches the whole input against the named rule instead of `main`.
+    pub fn execute_rule(&mut self, rule : &str, input : Vec<u8>) -> Matches<T> {
+        match self.entries.get(rule) {
+            Some(&entry) => self.execute_from(entry as isize, input),
+            None => Err(Error::RuleError(rule.to_string()))
+        }
+    }
+
+    fn execute_from(&mut self, entry : isize, input : Vec<u8>) -> Matches<T> {
+        let mut result = HashSet::new();
+        let skip_on = self.skip_on;
+        let (fail, i) = self.run(&input, entry, 0, skip_on, Some(&mut result))?;
+
+        if !fail && i == input.len() {
+            Ok(result.drain().collect())
+        } else {
+            Err(Error::MachineError(i))
+        }
     }
 
-    pub fn execute(&mut self, input : Vec<u8>) -> Result<Vec<(T, usize, usize)>, Error<T::Err>> {
+    // Skipping is toggled by the program as it runs, so the skip state lives in
+    // the stack frames and is restored on backtracking; `self.skip_on` is only
+    // the state each execution starts in.
+    fn run(&self,
+        input : &[u8],
+        start : isize,
+        start_position : usize,
+        mut skip_on : bool,
+        mut result : Option<&mut HashSet<(T, usize, usize)>>)
+        -> Result<(bool, usize), Error<T::Err>>
+    {
         let mut stack = Vec::new();
         let mut pos_stack = Vec::new();
-        let mut result = HashSet::new();
-        let mut pc = 0;
-        let mut i = 0;
+        // Named captures as (name, start, end). Every frame that can be
+        // returned to remembers how many there were when it was pushed.
+        let mut captures : Vec<(usize, usize, usize)> = Vec::new();
+        let mut pc = start;
+        let mut i = start_position;
         let mut fail = false;
+        let mut thrown : Option<usize> = None;
 
         loop {
             //println!("i: {}, fail: {}, pc: {}, \n {:?} \n {:?}", i, fail, pc, stack, pos_stack);
-            if self.skip_on {
-                while i < input.len() && self.skip_parser(input[i]) {
-                    i += 1;
+            if let Some(label) = thrown {
+                // A labeled failure unwinds past every backtrack entry, up to
+                // a recovery for its label, and resumes where it was thrown.
+                match stack.pop() {
+                    Some(StackFrame::Recovery(ret, s, labels, c)) if labels & (1 << label) != 0 => {
+                        pc = ret;
+                        skip_on = s;
+                        captures.truncate(c);
+                        thrown = None;
+                    },
+                    Some(StackFrame::Return(_, _)) => {
+                        pos_stack.pop();
+                    },
+                    Some(StackFrame::Skip(on)) => {
+                        skip_on = on;
+                    },
+                    Some(_) => { },
+                    None => return Err(Error::LabelError(self.labels[label].clone(), i))
                 }
-            }
-
-            if fail {
+            } else if fail {
                 if let Some(frame) = stack.pop() {
                     use self::StackFrame::*;
                     match frame {
-                        Backtrack(ret, j) => {
+                        Backtrack(ret, j, s, c) => {
                             pc = ret;
                             i = j;
+                            skip_on = s;
+                            captures.truncate(c);
                             fail = false;
                         },
-                        PrecedenceBacktrack(ret, a, j, jp, k, f, is_left) => {
+                        PrecedenceBacktrack(ret, a, j, jp, k, f, is_left, s, c) => {
+                            skip_on = s;
+                            captures.truncate(c);
                             if (jp.is_none() || i > jp.unwrap()) && i != j {
-                                stack.push(PrecedenceBacktrack(ret, a, j, Some(i), k, true, is_left));
+                                stack.push(PrecedenceBacktrack(ret, a, j, Some(i), k, true, is_left, s, c));
                                 pc = a;
                                 i = j;
                                 fail = false;
@@ -105,7 +188,7 @@ impl<T> Machine<T>
                                 
                                 if is_left {
                                     pc = self.jump_table[ret as usize];
-                                    while let Some(&StackFrame::Backtrack(_, _)) = stack.get(stack.len() - 1) {
+                                    while let Some(&StackFrame::Backtrack(_, _, _, _)) = stack.last() {
                                         stack.pop();
                                     }
                                 } else {
@@ -113,9 +196,13 @@ impl<T> Machine<T>
                                 }
                             }
                         },
-                        StackFrame::Return(_) => {
+                        StackFrame::Return(_, _) => {
                             pos_stack.pop();
-                        }
+                        },
+                        StackFrame::Skip(on) => {
+                            skip_on = on;
+                        },
+                        StackFrame::Counter(_) | StackFrame::Recovery(_, _, _, _) | StackFrame::Mark(_) => { }
                     }
                 } else {
                     break;
@@ -155,6 +242,14 @@ impl<T> Machine<T>
                             pc += j;
                         }
                     },
+                    CharFold(c) => {
+                        if i < input.len() && input[i].to_ascii_lowercase() == c {
+                            pc += 1;
+                            i += 1;
+                        } else {
+                            fail = true;
+                        }
+                    },
                     CharRange(l, r) => {
                         if i < input.len() && input[i] >= l && input[i] <= r {
                             pc += 1;
@@ -172,14 +267,14 @@ impl<T> Machine<T>
                         }
                     }
                     Choice(j) => {
-                        stack.push(StackFrame::Backtrack(pc + j, i));
+                        stack.push(StackFrame::Backtrack(pc + j, i, skip_on, captures.len()));
                         pc += 1;
                     }
                     Jump(j) => {
                         pc += j;
                     }
                     Call(j) => {
-                        stack.push(StackFrame::Return(pc + 1));
+                        stack.push(StackFrame::Return(pc + 1, captures.len()));
                         pc += j;
                     },
                     PrecedenceCall(n, k, is_left) => {
@@ -187,13 +282,13 @@ impl<T> Machine<T>
                         let stack_update = {
                             let mut result = false;
                             let memo = stack.iter().find(|&&x| match x {
-                                StackFrame::PrecedenceBacktrack(_, a, j, _, _, _, _) => {
+                                StackFrame::PrecedenceBacktrack(_, a, j, _, _, _, _, _, _) => {
                                     pc + n == a && i == j
                                 },
                                 _ => false
                             });
                             match memo {
-                                Some(&StackFrame::PrecedenceBacktrack(_, _, _, jp, kp, _, _)) => {
+                                Some(&StackFrame::PrecedenceBacktrack(_, _, _, jp, kp, _, _, _, _)) => {
                                     match jp {
                                         Some(jr) => {
                                             if k >= kp {
@@ -217,16 +312,26 @@ impl<T> Machine<T>
                             result
                         };
                         if stack_update {
-                            stack.push(StackFrame::PrecedenceBacktrack(pc_clone, pc_clone + n, i, None, k, false, is_left));
+                            stack.push(StackFrame::PrecedenceBacktrack(pc_clone, pc_clone + n, i, None, k, false, is_left, skip_on, captures.len()));
                         }
                     },
                     Return => {
+                        while let Some(&StackFrame::Skip(on)) = stack.last() {
+                            skip_on = on;
+                            stack.pop();
+                        }
                         if let Some(frame) = stack.pop() {
-                            if let StackFrame::Return(ret) = frame {
+                            // The named captures of a rule are only seen
+                            // until it returns, and those of an attempt to
+                            // grow a left-recursive rule only by that attempt.
+                            if let StackFrame::Return(ret, c) = frame {
                                 pc = ret;
-                            } else if let StackFrame::PrecedenceBacktrack(ret, a, j, jp, k, _, is_left) = frame {
+                                captures.truncate(c);
+                            } else if let StackFrame::PrecedenceBacktrack(ret, a, j, jp, k, _, is_left, s, c) = frame {
+                                skip_on = s;
+                                captures.truncate(c);
                                 if jp.is_none() || i > jp.unwrap() {
-                                    stack.push(StackFrame::PrecedenceBacktrack(ret, a, j, Some(i), k, false, is_left));
+                                    stack.push(StackFrame::PrecedenceBacktrack(ret, a, j, Some(i), k, false, is_left, s, c));
                                     pc = a;
                                     i = j;
                                 } else {
@@ -234,7 +339,7 @@ impl<T> Machine<T>
                                     
                                     if is_left {
                                         pc = self.jump_table[ret as usize];
-                                        while let Some(&StackFrame::Backtrack(_, _)) = stack.get(stack.len() - 1) {
+                                        while let Some(&StackFrame::Backtrack(_, _, _, _)) = stack.last() {
                                             stack.pop();
                                         }
                                     } else {
@@ -249,11 +354,11 @@ impl<T> Machine<T>
                         pc += j;
                     },
                     BackCommit(j) => {
-                        if let Some(frame) = stack.pop() {
-                            if let StackFrame::Backtrack(_, k) = frame {
-                                pc += j;
-                                i = k;
-                            }
+                        if let Some(StackFrame::Backtrack(_, k, s, c)) = stack.pop() {
+                            pc += j;
+                            i = k;
+                            skip_on = s;
+                            captures.truncate(c);
                         }
                     },
                     PartialCommit(j) => {
@@ -261,24 +366,32 @@ impl<T> Machine<T>
                             pc += j;
                             let pos = stack.len() - 1;
                             match stack[pos] {
-                                StackFrame::Backtrack(p, _) => { 
-                                    stack[pos] = StackFrame::Backtrack(p, i);
+                                StackFrame::Backtrack(p, _, _, _) => { 
+                                    stack[pos] = StackFrame::Backtrack(p, i, skip_on, captures.len());
                                 },
                                 _ => { }
                             }
                         }
                     },
                     PushPos(id) => {
-                        pos_stack.push((id, i));
+                        pos_stack.push((Some(id), i));
+                        pc += 1;
+                    },
+                    // Silent rules keep their place on the position stack, so
+                    // that it stays in step with the calls, but save nothing.
+                    PushSilent => {
+                        pos_stack.push((None, i));
                         pc += 1;
                     },
                     SavePos => {
-                        if let Some((id, j)) = pos_stack.pop() {
-                            if j != i {
-                                match T::from_str(self.rule_names[id].as_str()) {
-                                    Ok(marker) => result.insert((marker, j, i)),
-                                    Err(e) => return Err(Error::MarkerError(e))
-                                };
+                        if let Some((Some(id), j)) = pos_stack.pop() {
+                            if let Some(ref mut result) = result {
+                                if j != i {
+                                    match T::from_str(self.rule_names[id].as_str()) {
+                                        Ok(marker) => result.insert((marker, j, i)),
+                                        Err(e) => return Err(Error::MarkerError(e))
+                                    };
+                                }
                             }
                         }
                         pc += 1;
@@ -291,22 +404,85 @@ impl<T> Machine<T>
                         fail = true;
                     },
                     Stop => {
-                        if i < input.len() { fail = true; }
                         break;
                     },
+                    Skip => {
+                        if skip_on {
+                            i = self.skip_parser(input, i);
+                        }
+                        pc += 1;
+                    },
                     ToggleSkip => {
-                        self.skip_on = !self.skip_on;
+                        skip_on = !skip_on;
+                        pc += 1;
+                    },
+                    PushSkip(on) => {
+                        stack.push(StackFrame::Skip(skip_on));
+                        skip_on = on;
+                        pc += 1;
+                    },
+                    PopSkip => {
+                        if let Some(StackFrame::Skip(on)) = stack.pop() {
+                            skip_on = on;
+                        }
+                        pc += 1;
+                    },
+                    PushCounter => {
+                        stack.push(StackFrame::Counter(0));
                         pc += 1;
+                    },
+                    TestCounter(n, j) => {
+                        match stack.last() {
+                            Some(&StackFrame::Counter(count)) if count >= n => pc += j,
+                            _ => pc += 1
+                        }
+                    },
+                    IncrementCounter => {
+                        if let Some(&mut StackFrame::Counter(ref mut count)) = stack.last_mut() {
+                            *count += 1;
+                        }
+                        pc += 1;
+                    },
+                    PopCounter => {
+                        stack.pop();
+                        pc += 1;
+                    },
+                    Throw(label) => {
+                        thrown = Some(label);
+                    },
+                    Recover(j, labels) => {
+                        stack.push(StackFrame::Recovery(pc + j, skip_on, labels, captures.len()));
+                        pc += 1;
+                    },
+                    Mark => {
+                        stack.push(StackFrame::Mark(i));
+                        pc += 1;
+                    },
+                    Capture(id) => {
+                        if let Some(StackFrame::Mark(j)) = stack.pop() {
+                            captures.push((id, j, i));
+                        }
+                        pc += 1;
+                    },
+                    // Matches the text of the latest capture of the name, and
+                    // fails if there is none.
+                    BackReference(id) => {
+                        let text = captures.iter().rev()
+                            .find(|x| x.0 == id)
+                            .map(|&(_, j, k)| &input[j..k]);
+                        match text {
+                            Some(text) if input[i..].starts_with(text) => {
+                                i += text.len();
+                                pc += 1;
+                            },
+                            _ => fail = true
+                        }
                     }
                 }
             }
         }
 
-        if !fail && i == input.len() {
-            Ok(result.drain().collect())
-        } else {
-            Err(Error::MachineError(i))
-        }
+        Ok((fail, i))
     }
 
     pub fn get_jump_table(program : &Vec<Instruction>) -> Vec<isize> {
@@ -323,31 +499,34 @@ impl<T> Machine<T>
         result
     }
 
-    pub fn new(grammar : &str) -> Result<Machine<T>, usize> {
-        let mut token_result = parser::tokenize(grammar);
-        let mut parse_tree = parser::parse(token_result.0, token_result.1)?;
+    pub fn new(grammar : &str) -> Result<Machine<T>, Vec<parser::GrammarError>> {
+        let parse_tree = parser::read(grammar)?;
+        Ok(Machine::from_grammar(parse_tree))
+    }
+
+    pub fn from_path(path : &Path) -> Result<Machine<T>, Vec<parser::GrammarError>> {
+        let parse_tree = parser::read_path(path)?;
+        Ok(Machine::from_grammar(parse_tree))
+    }
+
+    pub(crate) fn from_grammar(mut parse_tree : ast::Grammar) -> Machine<T> {
         let program = parse_tree.compile();
 
-        let mut rules = token_result.2.drain().collect::<Vec<(Vec<u8>, i32)>>();
-        rules.sort_by(|a, b| a.1.cmp(&b.1));
-        let rules_map = rules.drain(..).map(|x| String::from_utf8(x.0).ok().unwrap()).collect();
+        let rules_map = parse_tree.rule_names();
         let jump_table = Machine::<T>::get_jump_table(&program);
+        let skip = parse_tree.skip_entry();
 
-        Ok(Machine {
+        Machine {
             program: program,
             rule_names: rules_map,
-            skip: vec![],
-            skip_on: false,
+            entries: parse_tree.entries(),
+            labels: parse_tree.labels.clone(),
+            capture_names: parse_tree.captures.clone(),
+            skip,
+            skip_on: skip.is_some(),
             jump_table: jump_table,
             marker: PhantomData
-        })
-    }
-
-    pub fn from_path(path : &Path) -> Result<Machine<T>, usize> {
-        let mut file = File::open(path).ok().expect("rip");
-        let mut contents = String::new();
-        file.read_to_string(&mut contents).ok();
-        Machine::new(contents.as_str())
+        }
     }
 }
 
@@ -364,7 +543,10 @@ mod tests {
         let mut machine = Machine::<String> {
             program: program,
             rule_names: rule_names,
-            skip: vec![],
+            entries: HashMap::new(),
+            labels: vec![],
+            capture_names: vec![],
+            skip: None,
             skip_on: false,
             jump_table: jump_table,
             marker: PhantomData
@@ -380,7 +562,7 @@ mod tests {
     }
 
     fn execute_test_with_skip(program : Vec<Instruction>,
-        skip : Vec<(u8, u8)>,
+        skip : usize,
         subjects : &Vec<&str>,
         expected : &Vec<bool>,
         rule_names : Vec<String>)
@@ -389,12 +571,15 @@ mod tests {
         let mut machine = Machine::<String> {
             program: program,
             rule_names: rule_names,
-            skip: vec![],
+            entries: HashMap::new(),
+            labels: vec![],
+            capture_names: vec![],
+            skip: None,
             skip_on: false,
             jump_table: jump_table,
             marker: PhantomData
         };
-        machine.skip = skip;
+        machine.skip = Some(skip);
         machine.skip_on = true;
         assert!(subjects.len() == expected.len());
         for i in 0..expected.len() {
@@ -602,6 +787,83 @@ mod tests {
         execute_test(program, &subjects, &expected, vec![]);
     }
 
+    #[test]
+    fn char_fold() { // main { 'ab'i }
+        let program = vec![
+            Instruction::Call(2),
+            Instruction::Stop,
+            Instruction::CharFold(b'a'),
+            Instruction::CharFold(b'b'),
+            Instruction::Return
+        ];
+        let subjects = vec!["ab", "AB", "aB", "Ab", "ac", "a", "abb"];
+        let expected = vec![true, true, true, true, false, false, false];
+        execute_test(program, &subjects, &expected, vec![]);
+    }
+
+    #[test]
+    fn counted_repetition() { // main { 'a'{2,3} }
+        let program = vec![
+            Instruction::Call(2),
+            Instruction::Stop,
+            Instruction::PushCounter,
+            Instruction::TestCounter(2, 4),
+            Instruction::Char(b'a'),
+            Instruction::IncrementCounter,
+            Instruction::Jump(-3),
+            Instruction::TestCounter(3, 6),
+            Instruction::Choice(5),
+            Instruction::Char(b'a'),
+            Instruction::Commit(1),
+            Instruction::IncrementCounter,
+            Instruction::Jump(-5),
+            Instruction::PopCounter,
+            Instruction::Return
+        ];
+        let subjects = vec!["aa", "aaa", "", "a", "aaaa"];
+        let expected = vec![true, true, false, false, false];
+        execute_test(program, &subjects, &expected, vec![]);
+    }
+
+    #[test]
+    fn labeled_failure() { // main { ('a' 'b'^l / 'a') /{l} 'c' }
+        let program = vec![
+            Instruction::Call(2),
+            Instruction::Stop,
+            Instruction::Recover(10, 1),
+            Instruction::Choice(7),
+            Instruction::Char(b'a'),
+            Instruction::Choice(3),
+            Instruction::Char(b'b'),
+            Instruction::Commit(2),
+            Instruction::Throw(0),
+            Instruction::Commit(2),
+            Instruction::Char(b'a'),
+            Instruction::Commit(2),
+            Instruction::Char(b'c'),
+            Instruction::Return
+        ];
+        let subjects = vec!["ab", "ac", "a", "c", "abc"];
+        let expected = vec![true, true, false, false, false];
+        execute_test(program, &subjects, &expected, vec![]);
+    }
+
+    #[test]
+    fn back_reference() { // main { $x:['a'..'b'] $x }
+        let program = vec![
+            Instruction::Call(2),
+            Instruction::Stop,
+            Instruction::Mark,
+            Instruction::CharRange(b'a', b'b'),
+            Instruction::Capture(0),
+            Instruction::BackReference(0),
+            Instruction::Return
+        ];
+        let subjects = vec!["aa", "bb", "ab", "a", "aab"];
+        let expected = vec![true, true, false, false, false];
+        execute_test(program, &subjects, &expected, vec![]);
+    }
+
     #[test]
     fn char_range_links() { // main { ['a'..'b''c'..'c''e'..'e']* }
         let program = vec![
@@ -622,36 +884,145 @@ mod tests {
     #[test]
     fn skip_parser() { // main { ('a';'b')* } skip { [' '] }
         let program = vec![
-            Instruction::Call(2),
-            Instruction::Stop,
-            Instruction::Choice(4),
-            Instruction::Char('a' as u8),
-            Instruction::Char('b' as u8),
-            Instruction::Commit(-3),
-            Instruction::Return
+            Instruction::Call(9),         // -- entry point (main)
+            Instruction::Skip,            //  |
+            Instruction::Stop,            // -'
+            Instruction::Call(2),         // -- entry point (skip)
+            Instruction::Stop,            // -'
+            Instruction::Choice(3),       // -- skip
+            Instruction::Char(b' '), //  |
+            Instruction::PartialCommit(-1), //  |
+            Instruction::Return,          // -'
+            Instruction::Choice(6),       // -- main
+            Instruction::Skip,            //  |
+            Instruction::Char(b'a'), //  |
+            Instruction::Skip,            //  |
+            Instruction::Char(b'b'), //  |
+            Instruction::Commit(-5),      //  |
+            Instruction::Return           // -'
         ];
-        let skip = vec![(' ' as u8, ' ' as u8)];
         let subjects = vec!["ababab", "ab a b ab", "ab a  b  a b", " a   b ", "c"];
         let expected = vec![true, true, true, true, false];
-        execute_test_with_skip(program, skip, &subjects, &expected, vec![]);
+        execute_test_with_skip(program, 3, &subjects, &expected, vec![]);
+    }
+
+    #[test]
+    fn skip_only_at_token_boundaries() { // main { 'ab' 'c' } skip { [' '] }
+        let program = vec![
+            Instruction::Call(9),         // -- entry point (main)
+            Instruction::Skip,            //  |
+            Instruction::Stop,            // -'
+            Instruction::Call(2),         // -- entry point (skip)
+            Instruction::Stop,            // -'
+            Instruction::Choice(3),       // -- skip
+            Instruction::Char(b' '), //  |
+            Instruction::PartialCommit(-1), //  |
+            Instruction::Return,          // -'
+            Instruction::Skip,            // -- main
+            Instruction::Char(b'a'), //  |
+            Instruction::Char(b'b'), //  |
+            Instruction::Skip,            //  |
+            Instruction::Char(b'c'), //  |
+            Instruction::Return           // -'
+        ];
+        let subjects = vec!["abc", " ab c ", "ab   c", "a bc", "a b c"];
+        let expected = vec![true, true, true, false, false];
+        execute_test_with_skip(program, 3, &subjects, &expected, vec![]);
     }
 
     #[test]
     fn skip_parser_with_toggle() { // main { #s;'a';' ';#s;'b' } skip { [' '] }
         let program = vec![
-            Instruction::Call(2),
-            Instruction::Stop,
-            Instruction::ToggleSkip,
-            Instruction::Char('a' as u8),
-            Instruction::Char(' ' as u8),
-            Instruction::ToggleSkip,
-            Instruction::Char('b' as u8),
-            Instruction::Return
+            Instruction::Call(9),         // -- entry point (main)
+            Instruction::Skip,            //  |
+            Instruction::Stop,            // -'
+            Instruction::Call(2),         // -- entry point (skip)
+            Instruction::Stop,            // -'
+            Instruction::Choice(3),       // -- skip
+            Instruction::Char(b' '), //  |
+            Instruction::PartialCommit(-1), //  |
+            Instruction::Return,          // -'
+            Instruction::Skip,            // -- main
+            Instruction::ToggleSkip,      //  |
+            Instruction::Char(b'a'), //  |
+            Instruction::Skip,            //  |
+            Instruction::Char(b' '), //  |
+            Instruction::ToggleSkip,      //  |
+            Instruction::Skip,            //  |
+            Instruction::Char(b'b'), //  |
+            Instruction::Return           // -'
         ];
-        let skip = vec![(' ' as u8, ' ' as u8)];
         let subjects = vec!["a b", "a    b", "   a   b    ", "ab"];
         let expected = vec![true, true, true, false];
-        execute_test_with_skip(program, skip, &subjects, &expected, vec![]);
+        execute_test_with_skip(program, 3, &subjects, &expected, vec![]);
+    }
+
+    #[test]
+    fn skip_restored_on_backtrack() { // main { #s;'a';!. / 'a';'b' } skip { [' '] }
+        let program = vec![
+            Instruction::Call(9),         // -- entry point (main)
+            Instruction::Skip,            //  |
+            Instruction::Stop,            // -'
+            Instruction::Call(2),         // -- entry point (skip)
+            Instruction::Stop,            // -'
+            Instruction::Choice(3),       // -- skip
+            Instruction::Char(b' '), //  |
+            Instruction::PartialCommit(-1), //  |
+            Instruction::Return,          // -'
+            Instruction::Choice(9),       // -- main
+            Instruction::ToggleSkip,      //  |
+            Instruction::Skip,            //  |
+            Instruction::Char(b'a'), //  |
+            Instruction::Choice(4),       //  |
+            Instruction::Skip,            //  |
+            Instruction::Any,             //  |
+            Instruction::FailTwice,       //  |
+            Instruction::Commit(5),       //  |
+            Instruction::Skip,            //  |
+            Instruction::Char(b'a'), //  |
+            Instruction::Skip,            //  |
+            Instruction::Char(b'b'), //  |
+            Instruction::Return           // -'
+        ];
+        let subjects = vec!["a b", "a", " a  b ", "ab", "a ", "a b c"];
+        let expected = vec![true, true, true, true, false, false];
+        execute_test_with_skip(program, 3, &subjects, &expected, vec![]);
+    }
+
+    #[test]
+    fn skip_parser_with_push_pop() { // main { @atomic('a';'b');'c' / 'a';'b';'d' } skip { [' '] }
+        let program = vec![
+            Instruction::Call(9),         // -- entry point (main)
+            Instruction::Skip,            //  |
+            Instruction::Stop,            // -'
+            Instruction::Call(2),         // -- entry point (skip)
+            Instruction::Stop,            // -'
+            Instruction::Choice(3),       // -- skip
+            Instruction::Char(b' '), //  |
+            Instruction::PartialCommit(-1), //  |
+            Instruction::Return,          // -'
+            Instruction::Choice(11),      // -- main
+            Instruction::Skip,            //  |
+            Instruction::PushSkip(false), //  |
+            Instruction::Skip,            //  |
+            Instruction::Char(b'a'), //  |
+            Instruction::Skip,            //  |
+            Instruction::Char(b'b'), //  |
+            Instruction::PopSkip,         //  |
+            Instruction::Skip,            //  |
+            Instruction::Char(b'c'), //  |
+            Instruction::Commit(7),       //  |
+            Instruction::Skip,            //  |
+            Instruction::Char(b'a'), //  |
+            Instruction::Skip,            //  |
+            Instruction::Char(b'b'), //  |
+            Instruction::Skip,            //  |
+            Instruction::Char(b'd'), //  |
+            Instruction::Return           // -'
+        ];
+        let subjects = vec!["ab c", " ab   c ", "a b d", "ab d", "a b c", "a b"];
+        let expected = vec![true, true, true, true, false, false];
+        execute_test_with_skip(program, 3, &subjects, &expected, vec![]);
     }
 
     #[test]
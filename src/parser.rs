@@ -1,5 +1,13 @@
-use std::collections::{HashMap};
+use std::error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::iter::Peekable;
+use std::str::CharIndices;
 use ast;
+use unicode;
 
 #[derive(Debug, Eq, PartialEq)]
 pub enum Token {
@@ -19,85 +27,245 @@ pub enum Token {
     Ambersand,
     Slash,
     Dash,
+    Caret,
     Colon,
+    Comma,
+    Tilde,
+    Dollar,
+    OpenAngle,
+    CloseAngle,
     Number(i32),
-    Name(i32),
-    Letter(u8)
+    Name(String),
+    Letter(char),
+    Byte(u8),
+    Ranges(String, Vec<(char, Option<char>)>),
+    Skip,
+    At,
+    Atomic,
+    Silent,
+    Inline,
+    Left,
+    Right,
+    NonAssoc,
+    Prefix,
+    Postfix,
+    Operators,
+    Utf8,
+    Insensitive
 }
 
-pub fn tokenize(grammar : &str) -> (Vec<Token>, i32, HashMap<Vec<u8>, i32>) {
-    let mut iterator = grammar.chars();
+/// A position in the grammar source, with lines and columns counted from 1.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub line : usize,
+    pub column : usize
+}
+
+/// An error found while reading a grammar. Errors in imported grammars name
+/// the file they were found in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GrammarError {
+    pub module : Option<String>,
+    pub span : Span,
+    pub rule : Option<String>,
+    pub expected : Option<String>,
+    pub message : String
+}
+
+pub struct Tokens {
+    pub tokens : Vec<Token>,
+    pub spans : Vec<Span>,
+    pub end : Span,
+    // Comments with the index of the token that follows them.
+    pub comments : Vec<(usize, Span, String)>
+}
+
+// The token index at which parsing failed and a description of what was
+// expected there.
+type Failure = (usize, &'static str);
+
+impl fmt::Display for GrammarError {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref module) = self.module {
+            write!(f, "{}:", module)?;
+        }
+        write!(f, "{}:{}: {}", self.span.line, self.span.column, self.message)?;
+        if let Some(ref rule) = self.rule {
+            write!(f, " (in rule `{}`)", rule)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for GrammarError {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+impl Span {
+    fn at(grammar : &str, offset : usize) -> Span {
+        Span { line: 1, column: 1 }.advance(grammar, 0, offset)
+    }
+
+    // The span of `offset`, counting on from this span at offset `from`.
+    fn advance(self, grammar : &str, from : usize, offset : usize) -> Span {
+        let mut span = self;
+        for x in grammar[from..offset].chars() {
+            if x == '\n' {
+                span.line += 1;
+                span.column = 1;
+            } else {
+                span.column += 1;
+            }
+        }
+        span
+    }
+}
+
+// The spans of increasing offsets, found in a single pass over the grammar.
+fn spans(grammar : &str, offsets : &[usize]) -> Vec<Span> {
+    let mut last = (0, Span { line: 1, column: 1 });
+    offsets.iter().map(|&x| {
+        last = (x, last.1.advance(grammar, last.0, x));
+        last.1
+    }).collect()
+}
+
+impl Tokens {
+    pub fn span(&self, index : usize) -> Span {
+        self.spans.get(index).cloned().unwrap_or(self.end)
+    }
+
+    pub fn get(&self, index : usize) -> Option<&Token> {
+        self.tokens.get(index)
+    }
+
+    fn describe(&self, index : usize) -> String {
+        match self.tokens.get(index) {
+            Some(&Token::OpenBrace) => "'{'".to_string(),
+            Some(&Token::CloseBrace) => "'}'".to_string(),
+            Some(&Token::OpenParen) => "'('".to_string(),
+            Some(&Token::CloseParen) => "')'".to_string(),
+            Some(&Token::OpenBracket) => "'['".to_string(),
+            Some(&Token::CloseBracket) => "']'".to_string(),
+            Some(&Token::SingleQuote) | Some(&Token::DoubleQuote) => "a quote".to_string(),
+            Some(&Token::Dot) => "'.'".to_string(),
+            Some(&Token::Plus) => "'+'".to_string(),
+            Some(&Token::Asterik) => "'*'".to_string(),
+            Some(&Token::Question) => "'?'".to_string(),
+            Some(&Token::Exclamation) => "'!'".to_string(),
+            Some(&Token::Ambersand) => "'&'".to_string(),
+            Some(&Token::Slash) => "'/'".to_string(),
+            Some(&Token::Dash) => "'-'".to_string(),
+            Some(&Token::Caret) => "'^'".to_string(),
+            Some(&Token::Colon) => "':'".to_string(),
+            Some(&Token::Comma) => "','".to_string(),
+            Some(&Token::Tilde) => "'~'".to_string(),
+            Some(&Token::Dollar) => "'$'".to_string(),
+            Some(&Token::OpenAngle) => "'<'".to_string(),
+            Some(&Token::CloseAngle) => "'>'".to_string(),
+            Some(&Token::Number(n)) => format!("the number {}", n),
+            Some(Token::Name(name)) => format!("the name `{}`", name),
+            Some(&Token::Letter(_)) => "a character".to_string(),
+            Some(&Token::Byte(_)) => "a byte".to_string(),
+            Some(&Token::Ranges(_, _)) => "a character class".to_string(),
+            Some(&Token::Skip) => "`skip`".to_string(),
+            Some(&Token::At) => "'@'".to_string(),
+            Some(&Token::Atomic) => "`@atomic`".to_string(),
+            Some(&Token::Silent) => "`@silent`".to_string(),
+            Some(&Token::Inline) => "`@inline`".to_string(),
+            Some(&Token::Left) => "`@left`".to_string(),
+            Some(&Token::Right) => "`@right`".to_string(),
+            Some(&Token::NonAssoc) => "`@nonassoc`".to_string(),
+            Some(&Token::Prefix) => "`@prefix`".to_string(),
+            Some(&Token::Postfix) => "`@postfix`".to_string(),
+            Some(&Token::Operators) => "`@operators`".to_string(),
+            Some(&Token::Utf8) => "`@utf8`".to_string(),
+            Some(&Token::Insensitive) => "'i'".to_string(),
+            None => "the end of the grammar".to_string()
+        }
+    }
+
+    fn error(&self, failure : Failure, rule : Option<String>) -> GrammarError {
+        let (index, expected) = failure;
+        GrammarError {
+            module: None,
+            span: self.span(index),
+            rule,
+            expected: Some(expected.to_string()),
+            message: format!("expected {}, found {}", expected, self.describe(index))
+        }
+    }
+}
+
+pub fn tokenize(grammar : &str) -> Result<Tokens, GrammarError> {
+    let mut iterator = grammar.char_indices().peekable();
     let mut tokens = vec![];
-    let mut name = vec![];
-    let mut i = 1;
+    let mut offsets = vec![];
+    let mut comments = vec![];
+    let mut name = String::new();
+    let mut name_start = 0;
+    let mut open = 0;
     let mut in_quote = false;
     let mut in_bracket = false;
-    let mut escaped = false;
-    let main = vec![b'm', b'a', b'i', b'n'];
-    let mut map = HashMap::new();
-    map.insert(main, 0);
+    let mut quote = '\'';
+    let mut utf8 = false;
 
-    while let Some(item) = iterator.next() {
+    while let Some((offset, item)) = iterator.next() {
         if in_quote || in_bracket {
-            if escaped {
-                match item {
-                    't' => tokens.push(Token::Letter(b'\t')),
-                    'r' => tokens.push(Token::Letter(b'\r')),
-                    'n' => tokens.push(Token::Letter(b'\n')),
-                    _ => tokens.push(Token::Letter(item as u8))
-                }
-                escaped = false;
-            } else {
-                match item {
-                    '-' => {
-                        if in_bracket {
-                            tokens.push(Token::Dash);
-                        } else {
-                            tokens.push(Token::Letter('-' as u8));
-                        }
-                    },
-                    '\'' if !in_bracket => {
-                        tokens.push(Token::SingleQuote); 
-                        in_quote = false;
-                    },
-                    '\"' if !in_bracket => {
-                        tokens.push(Token::DoubleQuote); 
-                        in_quote = false;
-                    },
-                    ']' if !in_quote => {
-                        tokens.push(Token::CloseBracket);
-                        in_bracket = false;
+            match item {
+                '^' if in_bracket && tokens.last() == Some(&Token::OpenBracket) => {
+                    tokens.push(Token::Caret);
+                },
+                '-' => {
+                    if in_bracket {
+                        tokens.push(Token::Dash);
+                    } else {
+                        tokens.push(Token::Letter('-'));
                     }
-                    '\\' => { escaped = true; },
-                    _ => tokens.push(Token::Letter(item as u8)),
+                },
+                '\'' if !in_bracket && quote == '\'' => {
+                    tokens.push(Token::SingleQuote); 
+                    in_quote = false;
+                    tokenize_insensitive(&mut iterator, &mut tokens);
+                },
+                '\"' if !in_bracket && quote == '\"' => {
+                    tokens.push(Token::DoubleQuote); 
+                    in_quote = false;
+                    tokenize_insensitive(&mut iterator, &mut tokens);
+                },
+                ']' if !in_quote => {
+                    tokens.push(Token::CloseBracket);
+                    in_bracket = false;
+                    tokenize_insensitive(&mut iterator, &mut tokens);
                 }
+                '[' if in_bracket && is_named_class(&iterator) => {
+                    tokens.push(tokenize_named_class(grammar, offset, &mut iterator)?);
+                },
+                '\\' if in_bracket && is_shorthand(&iterator) => {
+                    tokens.push(tokenize_shorthand(grammar, offset, &mut iterator, utf8)?);
+                },
+                '\\' => tokens.push(tokenize_escape(grammar, offset, &mut iterator, in_bracket)?),
+                _ => tokens.push(Token::Letter(item)),
+            }
+            if in_bracket {
+                check_range(grammar, &tokens, &offsets)?;
             }
         } else {
-            if !item.is_alphanumeric() && item != '_' && name.len() != 0 {
-                if name.iter().all(|&x| x >= b'0' && x <= b'9') {
-                    let mut i = 1;
-                    let mut result = 0;
-                    while let Some(x) = name.pop() {
-                        let n = (x - b'0') as i32;
-                        result += n*i;
-                        i *= 10;
-                    }
-                    tokens.push(Token::Number(result));
-                } else {
-                    let mut to_insert = false;
-                    let id = match map.get(&name) {
-                        Some(j) => *j,
-                        None => {
-                            let j = i;
-                            i += 1;
-                            to_insert = true;
-                            j
-                        }
-                    };
-                    if to_insert { map.insert(name.clone(), id); }
-                    tokens.push(Token::Name(id));
-                    name.clear();
-                }
+            // A dot between two names qualifies the second with a module.
+            let qualified = item == '.' && !name.is_empty()
+                && !name.chars().all(|x| x.is_ascii_digit())
+                && iterator.peek().is_some_and(|x| x.1.is_alphabetic() || x.1 == '_');
+            if qualified {
+                name.push(item);
+                continue;
+            }
+
+            if !item.is_alphanumeric() && item != '_' && !name.is_empty() {
+                tokenize_name(grammar, &name, name_start, &mut tokens, &mut utf8)?;
+                name.clear();
+                offsets.resize(tokens.len(), name_start);
             }
 
             match item {  
@@ -111,12 +279,51 @@ pub fn tokenize(grammar : &str) -> (Vec<Token>, i32, HashMap<Vec<u8>, i32>) {
                 '?' => tokens.push(Token::Question),
                 '!' => tokens.push(Token::Exclamation),
                 '&' => tokens.push(Token::Ambersand),
+                '/' if iterator.peek().map(|x| x.1) == Some('/') => {
+                    while let Some(&(_, next)) = iterator.peek() {
+                        if next == '\n' { break; }
+                        iterator.next();
+                    }
+                    let end = iterator.peek().map_or(grammar.len(), |x| x.0);
+                    comments.push((tokens.len(), offset, grammar[offset..end].trim_end().to_string()));
+                },
+                '/' if iterator.peek().map(|x| x.1) == Some('*') => {
+                    iterator.next();
+                    let mut last = ' ';
+                    loop {
+                        match iterator.next() {
+                            Some((end, '/')) if last == '*' => {
+                                comments.push((tokens.len(), offset, grammar[offset..end + 1].to_string()));
+                                break;
+                            },
+                            Some((_, next)) => last = next,
+                            None => {
+                                return Err(GrammarError {
+                                    module: None,
+                                    span: Span::at(grammar, offset),
+                                    rule: None,
+                                    expected: Some("'*/'".to_string()),
+                                    message: "unterminated block comment".to_string()
+                                });
+                            }
+                        }
+                    }
+                },
                 '/' => tokens.push(Token::Slash),
                 '-' => tokens.push(Token::Dash),
                 ':' => tokens.push(Token::Colon),
+                ',' => tokens.push(Token::Comma),
+                '~' => tokens.push(Token::Tilde),
+                '^' => tokens.push(Token::Caret),
+                '$' => tokens.push(Token::Dollar),
+                '\\' => tokens.push(tokenize_shorthand(grammar, offset, &mut iterator, utf8)?),
+                '<' => tokens.push(Token::OpenAngle),
+                '>' => tokens.push(Token::CloseAngle),
+                '@' => tokens.push(Token::At),
                 '[' => {
                     tokens.push(Token::OpenBracket);
                     in_bracket = true;
+                    open = offset;
                 },
                 ']' => {
                     tokens.push(Token::CloseBracket);
@@ -125,73 +332,939 @@ pub fn tokenize(grammar : &str) -> (Vec<Token>, i32, HashMap<Vec<u8>, i32>) {
                 '\'' => {
                     tokens.push(Token::SingleQuote);
                     in_quote = true;
+                    quote = item;
+                    open = offset;
                 },
                 '\"' => {
                     tokens.push(Token::DoubleQuote);
                     in_quote = true;
+                    quote = item;
+                    open = offset;
+                },
+                _ if item.is_alphanumeric() || item == '_' => {
+                    if name.is_empty() { name_start = offset; }
+                    name.push(item)
                 },
-                _ if item.is_alphanumeric() || item == '_' => name.push(item as u8),
                 _ => { }
             }
         }
+        offsets.resize(tokens.len(), offset);
     }
-    (tokens, i, map)
+
+    // A name may also end the grammar.
+    if !name.is_empty() {
+        tokenize_name(grammar, &name, name_start, &mut tokens, &mut utf8)?;
+        offsets.resize(tokens.len(), name_start);
+    }
+
+    let (tokens, offsets) = split_unqualified(tokens, offsets, &mut comments);
+
+    if in_quote || in_bracket {
+        let (expected, message) = if in_quote {
+            ("a closing quote", "unterminated literal")
+        } else {
+            ("']'", "unterminated character class")
+        };
+        return Err(GrammarError {
+            module: None,
+            span: Span::at(grammar, open),
+            rule: None,
+            expected: Some(expected.to_string()),
+            message: message.to_string()
+        });
+    }
+
+    let comment_offsets : Vec<usize> = comments.iter().map(|x| x.1).collect();
+    let comment_spans = spans(grammar, &comment_offsets);
+    Ok(Tokens {
+        tokens,
+        spans: spans(grammar, &offsets),
+        end: Span::at(grammar, grammar.len()),
+        comments: comments.into_iter().zip(comment_spans).map(|((i, _, text), span)| (i, span, text)).collect()
+    })
+}
+
+// Turns a run of name characters into a number, a modifier after '@', the
+// skip rule's name or a rule name. `utf8` is set by the `@utf8` modifier.
+fn tokenize_name(grammar : &str,
+    name : &str,
+    name_start : usize,
+    tokens : &mut Vec<Token>,
+    utf8 : &mut bool)
+    -> Result<(), GrammarError>
+{
+    if name.chars().all(|x| x.is_ascii_digit()) {
+        match name.parse() {
+            Ok(number) => tokens.push(Token::Number(number)),
+            Err(_) => {
+                return Err(GrammarError {
+                    module: None,
+                    span: Span::at(grammar, name_start),
+                    rule: None,
+                    expected: Some("a smaller number".to_string()),
+                    message: format!("the number {} is too large", name)
+                });
+            }
+        }
+    } else if tokens.last() == Some(&Token::At) {
+        let modifier = match name {
+            "atomic" => Token::Atomic,
+            "silent" => Token::Silent,
+            "inline" => Token::Inline,
+            "left" => Token::Left,
+            "right" => Token::Right,
+            "nonassoc" => Token::NonAssoc,
+            "prefix" => Token::Prefix,
+            "postfix" => Token::Postfix,
+            "operators" => Token::Operators,
+            "utf8" => Token::Utf8,
+            _ => {
+                return Err(GrammarError {
+                    module: None,
+                    span: Span::at(grammar, name_start),
+                    rule: None,
+                    expected: Some("a modifier".to_string()),
+                    message: format!("unknown modifier `@{}`", name)
+                });
+            }
+        };
+        // Properties can only be used after `@utf8`.
+        *utf8 = *utf8 || modifier == Token::Utf8;
+        tokens.pop();
+        tokens.push(modifier);
+    } else if name == "skip" {
+        tokens.push(Token::Skip);
+    } else {
+        tokens.push(Token::Name(name.to_string()));
+    }
+    Ok(())
+}
+
+/// Reads a grammar into a syntax tree with every rule reference resolved.
+/// Imported grammars are found relative to the working directory.
+pub fn read(grammar : &str) -> Result<ast::Grammar, Vec<GrammarError>> {
+    let mut grammar_object = ast::Grammar { rules: vec![], main: 0, skip: None, utf8: false, imports: vec![], labels: vec![], captures: vec![], comments: vec![] };
+    load(grammar, None, "", &mut grammar_object, &mut vec![])?;
+    grammar_object.expand()?;
+    grammar_object.resolve()?;
+    Ok(grammar_object)
+}
+
+/// Reads the grammar in a file, finding imported grammars relative to it.
+pub fn read_path(path : &Path) -> Result<ast::Grammar, Vec<GrammarError>> {
+    let (canonical, source) = read_file(path).map_err(|x| vec![GrammarError {
+        module: Some(path.display().to_string()),
+        span: Span::default(),
+        rule: None,
+        expected: None,
+        message: format!("cannot read the grammar: {}", x)
+    }])?;
+    let mut grammar_object = ast::Grammar { rules: vec![], main: 0, skip: None, utf8: false, imports: vec![], labels: vec![], captures: vec![], comments: vec![] };
+    let mut loading = vec![(canonical, path.to_path_buf())];
+    load(&source, Some(path), "", &mut grammar_object, &mut loading)?;
+    grammar_object.expand()?;
+    grammar_object.resolve()?;
+    Ok(grammar_object)
+}
+
+/// Prints a grammar in its canonical layout, keeping its comments. The
+/// grammar is only parsed, so the grammars it imports are not read.
+pub fn format(grammar : &str) -> Result<String, GrammarError> {
+    let tokens = tokenize(grammar)?;
+    Ok(parse(&tokens)?.to_string())
+}
+
+fn read_file(path : &Path) -> Result<(PathBuf, String), String> {
+    let canonical = fs::canonicalize(path).map_err(|x| x.to_string())?;
+    let mut file = File::open(&canonical).map_err(|x| x.to_string())?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents).map_err(|x| x.to_string())?;
+    Ok((canonical, contents))
 }
 
-pub fn parse(tokens : Vec<Token>, rule_count : i32) -> Result<ast::Grammar, usize> {
-    let mut grammar_object = ast::Grammar { rules: vec![], main: 0 };
-    let mut insert_order = vec![];
+// Parses a module and everything it imports into `grammar`, with the rules of
+// the module named under `prefix`. The modules currently being loaded are kept
+// in `loading` so that import cycles can be reported.
+fn load(source : &str,
+    module : Option<&Path>,
+    prefix : &str,
+    grammar : &mut ast::Grammar,
+    loading : &mut Vec<(PathBuf, PathBuf)>)
+    -> Result<(), Vec<GrammarError>>
+{
+    let name = module.map(|x| x.display().to_string());
+    let in_module = |mut error : GrammarError| {
+        error.module = name.clone();
+        vec![error]
+    };
+    let tokens = tokenize(source).map_err(&in_module)?;
+    let mut module_grammar = parse(&tokens).map_err(&in_module)?;
+    module_grammar.desugar().map_err(|x| x.into_iter().flat_map(&in_module).collect::<Vec<_>>())?;
+    for rule in &mut module_grammar.rules {
+        rule.module = name.clone();
+    }
+
+    let mut errors = vec![];
+    if let Some(mut skip) = module_grammar.skip.take() {
+        skip.module = name.clone();
+        if prefix.is_empty() {
+            grammar.skip = Some(skip);
+        } else {
+            errors.push(GrammarError {
+                module: name.clone(),
+                span: skip.span,
+                rule: Some(skip.name),
+                expected: None,
+                message: "only the root grammar may define a skip rule".to_string()
+            });
+        }
+    }
+
+    // Imported rules are matched in the mode of the root grammar, so only the
+    // root grammar may choose it.
+    if prefix.is_empty() {
+        grammar.utf8 = module_grammar.utf8;
+    } else if let Some(k) = tokens.tokens.iter().position(|x| *x == Token::Utf8) {
+        errors.push(GrammarError {
+            module: name.clone(),
+            span: tokens.span(k),
+            rule: None,
+            expected: None,
+            message: "only the root grammar may use `@utf8`".to_string()
+        });
+    }
+
+    let directory = module.and_then(|x| x.parent()).unwrap_or(Path::new(""));
+    for import in &module_grammar.imports {
+        let path = directory.join(&import.path);
+        let error = |message : String| {
+            GrammarError {
+                module: name.clone(),
+                span: import.span,
+                rule: None,
+                expected: None,
+                message
+            }
+        };
+        let (canonical, source) = match read_file(&path) {
+            Ok(x) => x,
+            Err(x) => {
+                errors.push(error(format!("cannot import `{}`: {}", import.path, x)));
+                continue;
+            }
+        };
+        if let Some(k) = loading.iter().position(|x| x.0 == canonical) {
+            let mut cycle : Vec<String> = loading[k..].iter().map(|x| x.1.display().to_string()).collect();
+            cycle.push(path.display().to_string());
+            errors.push(error(format!("import cycle: {}", cycle.join(" -> "))));
+            continue;
+        }
+        loading.push((canonical, path.clone()));
+        let qualified = format!("{}{}.", prefix, import.name);
+        if let Err(mut x) = load(&source, Some(&path), &qualified, grammar, loading) {
+            errors.append(&mut x);
+        }
+        loading.pop();
+    }
+
+    if prefix.is_empty() {
+        grammar.imports = mem::take(&mut module_grammar.imports);
+    }
+    grammar.import(module_grammar, prefix);
+    if errors.is_empty() { Ok(()) } else { Err(errors) }
+}
+
+// A dot joins a name to the module it is qualified with only if the grammar
+// imports a module under that name, so that `a.b` otherwise still means
+// `a . b`. The comments are moved along with the tokens they come before.
+fn split_unqualified(tokens : Vec<Token>, offsets : Vec<usize>, comments : &mut [(usize, usize, String)])
+    -> (Vec<Token>, Vec<usize>)
+{
+    let mut modules = vec![];
+    for k in 0..tokens.len() {
+        let quote = match (&tokens[k], tokens.get(k + 1)) {
+            (Token::Name(name), Some(quote)) if name == "import" => quote,
+            _ => continue
+        };
+        if *quote != Token::SingleQuote && *quote != Token::DoubleQuote {
+            continue;
+        }
+        let close = match tokens[k + 2..].iter().position(|x| x == quote) {
+            Some(j) => k + 2 + j,
+            None => continue
+        };
+        if let (Some(Token::Name(word)), Some(Token::Name(module))) = (tokens.get(close + 1), tokens.get(close + 2)) {
+            if word == "as" {
+                modules.push(module.clone());
+            }
+        }
+    }
+
+    let mut result = vec![];
+    let mut result_offsets = vec![];
+    let mut moved = vec![];
+    for (token, offset) in tokens.into_iter().zip(offsets) {
+        moved.push(result.len());
+        let parts = match token {
+            Token::Name(ref name) if name.contains('.') && !modules.iter().any(|x| name.starts_with(&format!("{}.", x))) => {
+                name.split('.').map(|x| x.to_string()).collect::<Vec<String>>()
+            },
+            _ => {
+                result.push(token);
+                result_offsets.push(offset);
+                continue;
+            }
+        };
+        let mut start = offset;
+        for (n, part) in parts.into_iter().enumerate() {
+            if n > 0 {
+                result.push(Token::Dot);
+                result_offsets.push(start);
+                start += 1;
+            }
+            let length = part.len();
+            result.push(if part == "skip" { Token::Skip } else { Token::Name(part) });
+            result_offsets.push(start);
+            start += length;
+        }
+    }
+    moved.push(result.len());
+    for comment in comments.iter_mut() {
+        comment.0 = moved[comment.0];
+    }
+    (result, result_offsets)
+}
+
+// Rejects a range in a class that was just completed with its upper bound if
+// it ends before it starts.
+fn check_range(grammar : &str, tokens : &[Token], offsets : &[usize]) -> Result<(), GrammarError> {
+    let n = tokens.len();
+    if n < 4 || tokens[n - 4] == Token::Dash {
+        return Ok(());
+    }
+    match (&tokens[n - 3], &tokens[n - 2], &tokens[n - 1]) {
+        (&Token::Letter(lower), &Token::Dash, &Token::Letter(upper)) if lower > upper => {
+            Err(GrammarError {
+                module: None,
+                span: Span::at(grammar, offsets[n - 3]),
+                rule: None,
+                expected: Some("a range in increasing order".to_string()),
+                message: format!("range `{}-{}` is reversed", lower.escape_default(), upper.escape_default())
+            })
+        },
+        _ => Ok(())
+    }
+}
+
+// Reads the escape sequence following the backslash at `start`. Byte escapes
+// above `\x7f` are raw bytes, which only make sense in literals.
+fn tokenize_escape(grammar : &str,
+    start : usize,
+    iterator : &mut Peekable<CharIndices>,
+    in_bracket : bool)
+    -> Result<Token, GrammarError>
+{
+    let error = |expected : &str, message : String| {
+        GrammarError {
+            module: None,
+            span: Span::at(grammar, start),
+            rule: None,
+            expected: Some(expected.to_string()),
+            message
+        }
+    };
+
+    let item = match iterator.next() {
+        Some((_, x)) => x,
+        None => return Err(error("an escape sequence", "incomplete escape sequence".to_string()))
+    };
+    match item {
+        't' => Ok(Token::Letter('\t')),
+        'r' => Ok(Token::Letter('\r')),
+        'n' => Ok(Token::Letter('\n')),
+        '0' => Ok(Token::Letter('\0')),
+        '\\' | '\'' | '\"' | '-' | '^' | '[' | ']' => Ok(Token::Letter(item)),
+        'x' => {
+            let (value, digits) = tokenize_hex(iterator, 2);
+            if digits != 2 {
+                return Err(error("two hex digits", "invalid byte escape".to_string()));
+            }
+            if value < 0x80 {
+                Ok(Token::Letter(value as u8 as char))
+            } else if in_bracket {
+                Err(error("a character", format!(
+                    "byte escape `\\x{:02x}` is not a character, use `\\u{{{:x}}}` in classes", value, value)))
+            } else {
+                Ok(Token::Byte(value as u8))
+            }
+        },
+        'u' => {
+            let open = iterator.next().map(|x| x.1) == Some('{');
+            let (value, digits) = tokenize_hex(iterator, 6);
+            let close = iterator.next().map(|x| x.1) == Some('}');
+            match ::std::char::from_u32(value) {
+                Some(x) if open && close && digits > 0 => Ok(Token::Letter(x)),
+                _ => Err(error("a code point in the form `\\u{...}`", "invalid unicode escape".to_string()))
+            }
+        },
+        _ => Err(error("an escape sequence", format!("unknown escape sequence `\\{}`", item)))
+    }
+}
+
+// An `i` directly after a literal or class makes it case-insensitive, unless
+// it starts a name.
+fn tokenize_insensitive(iterator : &mut Peekable<CharIndices>, tokens : &mut Vec<Token>) {
+    let mut ahead = iterator.clone();
+    if let Some((_, 'i')) = ahead.next() {
+        match ahead.next() {
+            Some((_, x)) if x.is_alphanumeric() || x == '_' => { },
+            _ => {
+                iterator.next();
+                tokens.push(Token::Insensitive);
+            }
+        }
+    }
+}
+
+fn is_shorthand(iterator : &Peekable<CharIndices>) -> bool {
+    matches!(iterator.clone().peek(), Some(&(_, 'd')) | Some(&(_, 'w')) | Some(&(_, 's')) | Some(&(_, 'p')))
+}
+
+// The shorthands `\d`, `\w` and `\s` stand for ASCII digits, word characters
+// and white space, and `\p{...}` for a Unicode property in UTF-8 grammars.
+fn tokenize_shorthand(grammar : &str,
+    start : usize,
+    iterator : &mut Peekable<CharIndices>,
+    utf8 : bool)
+    -> Result<Token, GrammarError>
+{
+    let error = |expected : &str, message : String| {
+        GrammarError {
+            module: None,
+            span: Span::at(grammar, start),
+            rule: None,
+            expected: Some(expected.to_string()),
+            message
+        }
+    };
+
+    let name = match iterator.next() {
+        Some((_, 'd')) => "digit".to_string(),
+        Some((_, 'w')) => "word".to_string(),
+        Some((_, 's')) => "space".to_string(),
+        Some((_, 'p')) => {
+            if iterator.next().map(|x| x.1) != Some('{') {
+                return Err(error("a property in the form `\\p{...}`", "invalid unicode property".to_string()));
+            }
+            let mut name = String::new();
+            loop {
+                match iterator.next() {
+                    Some((_, '}')) => break,
+                    Some((_, x)) if x.is_alphanumeric() || x == '_' => name.push(x),
+                    _ => return Err(error("a property in the form `\\p{...}`", "invalid unicode property".to_string()))
+                }
+            }
+            if !utf8 {
+                return Err(error("`@utf8` before the property",
+                    format!("unicode property `\\p{{{}}}` needs a UTF-8 grammar", name)));
+            }
+            let text = format!("\\p{{{}}}", name);
+            return match unicode_property(&name) {
+                Some(ranges) => Ok(Token::Ranges(text, ranges)),
+                None => Err(error("a unicode property", format!("unknown unicode property `{}`", name)))
+            };
+        },
+        Some((_, x)) => return Err(error("a class shorthand", format!("unknown class shorthand `\\{}`", x))),
+        None => return Err(error("a class shorthand", "incomplete class shorthand".to_string()))
+    };
+    let text = format!("\\{}", &name[..1]);
+    Ok(Token::Ranges(text, named_class(&name).unwrap()))
+}
+
+// Whether a `[` in a class starts a named class such as `[:alpha:]`, so that
+// a class like `[[:]` keeps its meaning.
+fn is_named_class(iterator : &Peekable<CharIndices>) -> bool {
+    let mut ahead = iterator.clone();
+    if ahead.next().map(|x| x.1) != Some(':') {
+        return false;
+    }
+    let mut letters = 0;
+    loop {
+        match ahead.next().map(|x| x.1) {
+            Some(x) if x.is_ascii_alphabetic() => letters += 1,
+            Some(':') => return letters > 0 && ahead.next().map(|x| x.1) == Some(']'),
+            _ => return false
+        }
+    }
+}
+
+// A named class such as `[:alpha:]`, written inside of a class.
+fn tokenize_named_class(grammar : &str, start : usize, iterator : &mut Peekable<CharIndices>)
+    -> Result<Token, GrammarError>
+{
+    iterator.next();
+    let mut name = String::new();
+    while let Some(&(_, x)) = iterator.peek() {
+        if !x.is_alphabetic() { break; }
+        name.push(x);
+        iterator.next();
+    }
+    iterator.next();
+    iterator.next();
+    match named_class(&name) {
+        Some(ranges) => Ok(Token::Ranges(format!("[:{}:]", name), ranges)),
+        None => Err(GrammarError {
+            module: None,
+            span: Span::at(grammar, start),
+            rule: None,
+            expected: Some("a class name such as `[:alpha:]`".to_string()),
+            message: format!("unknown character class `[:{}:]`", name)
+        })
+    }
+}
+
+fn named_class(name : &str) -> Option<Vec<(char, Option<char>)>> {
+    let ranges : &[(char, char)] = match name {
+        "alpha" => &[('A', 'Z'), ('a', 'z')],
+        "digit" => &[('0', '9')],
+        "alnum" => &[('0', '9'), ('A', 'Z'), ('a', 'z')],
+        "upper" => &[('A', 'Z')],
+        "lower" => &[('a', 'z')],
+        "space" => &[('\t', '\r'), (' ', ' ')],
+        "blank" => &[('\t', '\t'), (' ', ' ')],
+        "xdigit" => &[('0', '9'), ('A', 'F'), ('a', 'f')],
+        "punct" => &[('!', '/'), (':', '@'), ('[', '`'), ('{', '~')],
+        "word" => &[('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')],
+        "cntrl" => &[('\0', '\x1f'), ('\x7f', '\x7f')],
+        "print" => &[(' ', '~')],
+        "graph" => &[('!', '~')],
+        _ => return None
+    };
+    Some(ranges.iter().map(|&(left, right)| (left, Some(right))).collect())
+}
+
+fn unicode_property(name : &str) -> Option<Vec<(char, Option<char>)>> {
+    let ranges = unicode::property(name)?;
+    Some(ranges.into_iter()
+        .map(|(left, right)| (char::from_u32(left).unwrap(), char::from_u32(right)))
+        .collect())
+}
+
+fn tokenize_hex(iterator : &mut Peekable<CharIndices>, max : usize) -> (u32, usize) {
+    let mut value = 0;
+    let mut digits = 0;
+    while digits < max {
+        match iterator.peek().and_then(|x| x.1.to_digit(16)) {
+            Some(x) => value = value * 16 + x,
+            None => break
+        }
+        iterator.next();
+        digits += 1;
+    }
+    (value, digits)
+}
+
+pub fn parse(tokens : &Tokens) -> Result<ast::Grammar, GrammarError> {
+    let mut grammar_object = ast::Grammar { rules: vec![], main: 0, skip: None, utf8: false, imports: vec![], labels: vec![], captures: vec![], comments: vec![] };
     let mut i = 0;
+    let mut comment = 0;
 
     while let Some(token) = tokens.get(i) {
-        if let &Token::Name(id) = token {
-            i += 1;
-            if let Some(brace_token) = tokens.get(i) {
+        // Comments around directives are kept at the top of the grammar.
+        if is_import(i, tokens) || token == &Token::Utf8 {
+            if is_import(i, tokens) {
+                let import = parse_import(&mut i, tokens).map_err(|x| tokens.error(x, None))?;
+                grammar_object.imports.push(import);
+            } else {
+                // A `@utf8` directive makes `.` match a whole code point.
+                grammar_object.utf8 = true;
                 i += 1;
-                if brace_token == &Token::OpenBrace {
-                    let pattern = parse_expression(&mut i, &tokens);
-                    match tokens.get(i) {
-                        Some(&Token::CloseBrace) => i += 1,
-                        _ => return Err(i)
-                    }
-                    match pattern {
-                        Ok(p) => insert_order.push((id, p)),
-                        Err(x) => return Err(x)
-                    }
-                } else {
-                    return Err(i);
+            }
+            while let Some(&(t, span, ref text)) = tokens.comments.get(comment) {
+                if t > i || (t == i && tokens.span(t - 1).line != span.line) {
+                    break;
                 }
-            } else {
-                return Err(i);
-            } 
+                grammar_object.comments.push(ast::Comment { line: 0, trailing: false, text: text.clone() });
+                comment += 1;
+            }
+            continue;
+        }
+
+        // Modifiers may be given in any order before the rule name.
+        let first = i;
+        let (mut atomic, mut silent, mut inline) = (false, false, false);
+        let mut operand = None;
+        let mut modifier = None;
+        loop {
+            match tokens.get(i) {
+                Some(&Token::Atomic) => atomic = true,
+                Some(&Token::Silent) => silent = true,
+                Some(&Token::Inline) => inline = true,
+                Some(&Token::Operators) => {
+                    operand = Some(parse_operand(i + 1, tokens).map_err(|x| tokens.error(x, None))?);
+                    modifier = tokens.get(i);
+                    i += 4;
+                    continue;
+                },
+                _ => break
+            }
+            modifier = tokens.get(i);
+            i += 1;
+        }
+
+        if let Some(Token::Name(name)) = tokens.get(i) {
+            let rule = Some(name.clone());
+            let start = i;
+            i += 1;
+            let parameters = match parse_parameters(&mut i, tokens) {
+                Ok(x) => x,
+                Err(x) => return Err(tokens.error(x, rule))
+            };
+            let open = i;
+            match tokens.get(i) {
+                Some(&Token::OpenBrace) => i += 1,
+                _ => return Err(tokens.error((i, "'{' to begin the rule"), rule))
+            }
+            let table = operand.is_some();
+            let pattern = match operand {
+                Some(operand) => parse_operators(&mut i, tokens, name, operand)?,
+                None => match parse_expression(&mut i, tokens) {
+                    Ok(p) => p,
+                    Err(x) => return Err(tokens.error(x, rule))
+                }
+            };
+            match tokens.get(i) {
+                Some(&Token::CloseBrace) => i += 1,
+                _ => return Err(tokens.error((i, "'}' to end the rule"), rule))
+            }
+            let mut rule = ast::Rule {
+                name: name.clone(),
+                span: tokens.span(start),
+                module: None,
+                parameters,
+                silent,
+                inline,
+                pattern: if atomic { ast::Pattern::Atomic(Box::new(pattern)) } else { pattern },
+                comments: vec![]
+            };
+            attach_comments(tokens, &mut comment, &mut rule, (first, open, i), table);
+            grammar_object.rules.push(rule);
+        } else if let Some(modifier) = modifier {
+            let expected = match *modifier {
+                Token::Silent => "a rule name after `@silent`",
+                Token::Inline => "a rule name after `@inline`",
+                Token::Operators => "a rule name after `@operators`",
+                _ => "a rule name after `@atomic`"
+            };
+            return Err(tokens.error((i, expected), None));
+        } else if let &Token::Skip = token {
+            let rule = Some("skip".to_string());
+            let start = i;
+            i += 1;
+            let open = i;
+            match tokens.get(i) {
+                Some(&Token::OpenBrace) => i += 1,
+                _ => return Err(tokens.error((i, "'{' to begin the rule"), rule))
+            }
+            let pattern = match parse_expression(&mut i, tokens) {
+                Ok(p) => p,
+                Err(x) => return Err(tokens.error(x, rule))
+            };
+            match tokens.get(i) {
+                Some(&Token::CloseBrace) => i += 1,
+                _ => return Err(tokens.error((i, "'}' to end the rule"), rule))
+            }
+            if grammar_object.skip.is_some() {
+                return Err(GrammarError {
+                    module: None,
+                    span: tokens.span(start),
+                    rule,
+                    expected: None,
+                    message: "the skip rule is defined more than once".to_string()
+                });
+            }
+            let mut skip = ast::Rule {
+                name: "skip".to_string(),
+                span: tokens.span(start),
+                module: None,
+                parameters: vec![],
+                silent: false,
+                inline: false,
+                pattern,
+                comments: vec![]
+            };
+            attach_comments(tokens, &mut comment, &mut skip, (start, open, i), false);
+            grammar_object.skip = Some(skip);
         } else {
-            return Err(i);
+            return Err(tokens.error((i, "a rule definition"), None));
         }
     }
 
-    insert_order.sort_by(|a, b| a.0.cmp(&b.0));
+    for (_, _, text) in &tokens.comments[comment..] {
+        let line = if grammar_object.rules.is_empty() && grammar_object.skip.is_none() { 0 } else { 1 };
+        grammar_object.comments.push(ast::Comment { line, trailing: false, text: text.clone() });
+    }
+    Ok(grammar_object)
+}
 
-    if insert_order.iter().map(|x| x.0).eq(0..rule_count) {
-        grammar_object.rules = insert_order.drain(..).map(|x| x.1).collect();
-        Ok(grammar_object)
-    } else {
-        Err(i)
+// Gives a rule the comments written before its end, from `comment` onwards.
+// Those on their own lines go before the rule or before the alternative that
+// follows them, and those after some text go at the end of its line. The
+// rule's tokens are `first..end`, with its body opening at `open`.
+fn attach_comments(tokens : &Tokens,
+    comment : &mut usize,
+    rule : &mut ast::Rule,
+    (first, open, end) : (usize, usize, usize),
+    table : bool)
+{
+    let starts = alternative_starts(tokens, open, end - 1, table);
+    let count = starts.len();
+    while let Some(&(t, span, ref text)) = tokens.comments.get(*comment) {
+        let same_line = t > 0 && tokens.span(t - 1).line == span.line;
+        if t > end || (t == end && !same_line) {
+            break;
+        }
+        let (line, trailing) = if t == end {
+            (count + 1, true)
+        } else if t <= first {
+            (0, false)
+        } else if t <= open || (same_line && t == open + 1) {
+            (0, true)
+        } else if same_line {
+            (starts.iter().filter(|&&x| x < t).count(), true)
+        } else {
+            (starts.iter().position(|&x| x >= t).map_or(count + 1, |x| x + 1), false)
+        };
+        rule.comments.push(ast::Comment { line, trailing, text: text.clone() });
+        *comment += 1;
     }
 }
 
-fn parse_expression(i : &mut usize, tokens : &Vec<Token>) -> Result<ast::Pattern, usize> {
-    let first_sequence = match parse_sequence(i, tokens) {
-        Ok(p) => p,
-        Err(x) => return Err(x)
+// Finds where each top-level alternative of the body between `open` and
+// `close` starts, or each level of an operator table.
+fn alternative_starts(tokens : &Tokens, open : usize, close : usize, table : bool) -> Vec<usize> {
+    let mut starts = if table { vec![] } else { vec![open + 1] };
+    let mut depth = 0;
+    let mut j = open + 1;
+    while j < close {
+        match tokens.get(j) {
+            Some(&Token::OpenParen) | Some(&Token::OpenAngle) | Some(&Token::OpenBrace) => depth += 1,
+            Some(&Token::CloseParen) | Some(&Token::CloseAngle) | Some(&Token::CloseBrace) => depth -= 1,
+            Some(&Token::Slash) if depth == 0 && !table => {
+                if is_recovery(j, tokens) {
+                    while tokens.get(j) != Some(&Token::CloseBrace) {
+                        j += 1;
+                    }
+                }
+                starts.push(j + 1);
+            },
+            Some(&Token::Left) | Some(&Token::Right) | Some(&Token::NonAssoc)
+            | Some(&Token::Prefix) | Some(&Token::Postfix) if depth == 0 && table => starts.push(j),
+            _ => { }
+        }
+        j += 1;
+    }
+    starts
+}
+
+// Parses the `(atom)` after `@operators`, naming the rule that supplies the
+// operands of the table.
+fn parse_operand(i : usize, tokens : &Tokens) -> Result<ast::Pattern, Failure> {
+    if tokens.get(i) != Some(&Token::OpenParen) {
+        return Err((i, "'(' before the operand rule"));
+    }
+    let name = match tokens.get(i + 1) {
+        Some(Token::Name(name)) => name.clone(),
+        _ => return Err((i + 1, "an operand rule"))
+    };
+    if tokens.get(i + 2) != Some(&Token::CloseParen) {
+        return Err((i + 2, "')' after the operand rule"));
+    }
+    Ok(ast::Pattern::Reference(name, -1, tokens.span(i + 1)))
+}
+
+// Parses the levels of an operator table, loosest first, and reports an
+// operator that is at more than one level. The table is turned into
+// alternatives of the rule by `ast::Grammar::desugar`.
+fn parse_operators(i : &mut usize, tokens : &Tokens, name : &str, operand : ast::Pattern) -> Result<ast::Pattern, GrammarError> {
+    let rule = Some(name.to_string());
+
+    // Operators seen so far, split by whether they start the expression or
+    // follow an operand, along with their level.
+    let mut prefixes : Vec<(ast::Pattern, usize)> = vec![];
+    let mut infixes : Vec<(ast::Pattern, usize)> = vec![];
+    let mut levels = vec![];
+    loop {
+        let fixity = match tokens.get(*i) {
+            Some(&Token::Left) => ast::Fixity::Left,
+            Some(&Token::Right) => ast::Fixity::Right,
+            Some(&Token::NonAssoc) => ast::Fixity::NonAssoc,
+            Some(&Token::Prefix) => ast::Fixity::Prefix,
+            Some(&Token::Postfix) => ast::Fixity::Postfix,
+            _ if levels.is_empty() => return Err(tokens.error((*i, "an operator level"), rule)),
+            _ => break
+        };
+        *i += 1;
+        let level = levels.len() + 1;
+
+        let mut operators = vec![];
+        loop {
+            let position = *i;
+            let operator = parse_sequence(i, tokens).map_err(|x| tokens.error(x, rule.clone()))?;
+            let key = operator.without_spans();
+            let seen = if fixity == ast::Fixity::Prefix { &mut prefixes } else { &mut infixes };
+            if let Some(&(_, other)) = seen.iter().find(|x| x.0 == key) {
+                return Err(GrammarError {
+                    module: None,
+                    span: tokens.span(position),
+                    rule,
+                    expected: None,
+                    message: format!("{} is already at level {}", describe_operator(&operator), other)
+                });
+            }
+            seen.push((key, level));
+            operators.push(Box::new(operator));
+            if tokens.get(*i) != Some(&Token::Slash) || is_recovery(*i, tokens) {
+                break;
+            }
+            *i += 1;
+        }
+        levels.push((fixity, operators));
+    }
+    Ok(ast::Pattern::Operators(Box::new(operand), levels))
+}
+
+fn describe_operator(operator : &ast::Pattern) -> String {
+    match *operator {
+        ast::Pattern::Reference(ref name, _, _) => format!("the operator `{}`", name),
+        ast::Pattern::CharSequence(ref bytes, _) => match ::std::str::from_utf8(bytes) {
+            Ok(text) => format!("the operator '{}'", text),
+            Err(_) => "the operator".to_string()
+        },
+        _ => "the operator".to_string()
+    }
+}
+
+fn is_import(i : usize, tokens : &Tokens) -> bool {
+    match (tokens.get(i), tokens.get(i + 1)) {
+        (Some(&Token::Name(ref name)), Some(&Token::SingleQuote))
+        | (Some(&Token::Name(ref name)), Some(&Token::DoubleQuote)) => name == "import",
+        _ => false
+    }
+}
+
+fn parse_import(i : &mut usize, tokens : &Tokens) -> Result<ast::Import, Failure> {
+    let span = tokens.span(*i);
+    *i += 1;
+    let path = match parse_literal(i, tokens)? {
+        ast::Pattern::CharSequence(ref bytes, false) => {
+            match String::from_utf8(bytes.clone()) {
+                Ok(path) => path,
+                Err(_) => return Err((*i - 1, "a path"))
+            }
+        },
+        _ => return Err((*i - 1, "a path"))
     };
+    match tokens.get(*i) {
+        Some(Token::Name(x)) if x == "as" => *i += 1,
+        _ => return Err((*i, "`as` after the imported path"))
+    }
+    match tokens.get(*i) {
+        Some(Token::Name(name)) if !name.contains('.') => {
+            *i += 1;
+            Ok(ast::Import { path, name: name.clone(), span })
+        },
+        _ => Err((*i, "a module name"))
+    }
+}
+
+fn parse_parameters(i : &mut usize, tokens : &Tokens) -> Result<Vec<String>, Failure> {
+    let mut parameters = vec![];
+    if tokens.get(*i) != Some(&Token::OpenAngle) {
+        return Ok(parameters);
+    }
+    loop {
+        *i += 1;
+        match tokens.get(*i) {
+            Some(Token::Name(name)) if !name.contains('.') => {
+                *i += 1;
+                parameters.push(name.clone());
+            },
+            _ => return Err((*i, "a parameter name"))
+        }
+        match tokens.get(*i) {
+            Some(&Token::Comma) => { },
+            Some(&Token::CloseAngle) => {
+                *i += 1;
+                return Ok(parameters);
+            },
+            _ => return Err((*i, "',' or '>' after a parameter"))
+        }
+    }
+}
+
+fn parse_arguments(i : &mut usize, tokens : &Tokens) -> Result<Vec<Box<ast::Pattern>>, Failure> {
+    let mut arguments = vec![];
+    loop {
+        *i += 1;
+        arguments.push(Box::new(parse_expression(i, tokens)?));
+        match tokens.get(*i) {
+            Some(&Token::Comma) => { },
+            Some(&Token::CloseAngle) => {
+                *i += 1;
+                return Ok(arguments);
+            },
+            _ => return Err((*i, "',' or '>' after an argument"))
+        }
+    }
+}
+
+// A recovery `a /{label} b` binds looser than an ordinary choice, so it
+// catches the labels thrown by any of the alternatives before it.
+fn parse_expression(i : &mut usize, tokens : &Tokens) -> Result<ast::Pattern, Failure> {
+    let mut result = parse_choice(i, tokens)?;
+    while is_recovery(*i, tokens) {
+        *i += 1;
+        let labels = parse_labels(i, tokens)?;
+        let recovery = parse_choice(i, tokens)?;
+        result = ast::Pattern::Recover(Box::new(result), Box::new(recovery), labels, 0);
+    }
+    Ok(result)
+}
+
+fn is_recovery(i : usize, tokens : &Tokens) -> bool {
+    tokens.get(i) == Some(&Token::Slash) && tokens.get(i + 1) == Some(&Token::OpenBrace)
+}
+
+fn parse_labels(i : &mut usize, tokens : &Tokens) -> Result<Vec<String>, Failure> {
+    let mut labels = vec![];
+    loop {
+        *i += 1;
+        match tokens.get(*i) {
+            Some(Token::Name(name)) if !name.contains('.') => {
+                *i += 1;
+                labels.push(name.clone());
+            },
+            _ => return Err((*i, "a label name"))
+        }
+        match tokens.get(*i) {
+            Some(&Token::Comma) => { },
+            Some(&Token::CloseBrace) => {
+                *i += 1;
+                return Ok(labels);
+            },
+            _ => return Err((*i, "',' or '}' after a label"))
+        }
+    }
+}
+
+fn parse_choice(i : &mut usize, tokens : &Tokens) -> Result<ast::Pattern, Failure> {
+    let first_sequence = parse_alternative(i, tokens)?;
     let mut patterns = vec![first_sequence];
 
-    while tokens.get(*i) == Some(&Token::Slash) {
+    while tokens.get(*i) == Some(&Token::Slash) && !is_recovery(*i, tokens) {
         *i += 1;
-        let sequence = match parse_sequence(i, tokens) {
-            Ok(p) => p,
-            Err(x) => return Err(x)
-        };
+        let sequence = parse_alternative(i, tokens)?;
         patterns.push(sequence);
     }
 
@@ -210,7 +1283,49 @@ fn parse_expression(i : &mut usize, tokens : &Vec<Token>) -> Result<ast::Pattern
     }
 }
 
-fn parse_sequence(i : &mut usize, tokens : &Vec<Token>) -> Result<ast::Pattern, usize> {
+// An alternative annotated with `@left(n)`, `@right(n)` or `@nonassoc(n)` is
+// an operator at level `n`, with the rule itself as the operand at one or both
+// of its ends. Its operands are given their precedences along with the rest of
+// the rule by `ast::Grammar::desugar_operators`.
+fn parse_alternative(i : &mut usize, tokens : &Tokens) -> Result<ast::Pattern, Failure> {
+    let fixity = match tokens.get(*i) {
+        Some(&Token::Left) => ast::Fixity::Left,
+        Some(&Token::Right) => ast::Fixity::Right,
+        Some(&Token::NonAssoc) => ast::Fixity::NonAssoc,
+        _ => return parse_sequence(i, tokens)
+    };
+    *i += 1;
+    if tokens.get(*i) != Some(&Token::OpenParen) {
+        return Err((*i, "'(' before the precedence level"));
+    }
+    *i += 1;
+    let level = match tokens.get(*i) {
+        Some(&Token::Number(n)) => n,
+        _ => return Err((*i, "a precedence level"))
+    };
+    *i += 1;
+    if tokens.get(*i) != Some(&Token::CloseParen) {
+        return Err((*i, "')' after the precedence level"));
+    }
+    *i += 1;
+
+    let start = *i;
+    let data = match parse_sequence(i, tokens)? {
+        ast::Pattern::Sequence(data) => data,
+        _ => return Err((start, "an operator with its operands"))
+    };
+    let last = data.len() - 1;
+    let operands = [0, last].iter().filter(|&&k| match *data[k] {
+        ast::Pattern::Reference(_, x, _) => x == -1,
+        _ => false
+    }).count();
+    if operands == 0 || (fixity == ast::Fixity::NonAssoc && (operands < 2 || last < 2)) {
+        return Err((start, "an operator with its operands"));
+    }
+    Ok(ast::Pattern::Operator(fixity, level, data))
+}
+
+fn parse_sequence(i : &mut usize, tokens : &Tokens) -> Result<ast::Pattern, Failure> {
     let first_prefix = match parse_prefix(i, tokens) {
         Ok(p) => p,
         Err(x) => return Err(x)
@@ -224,7 +1339,12 @@ fn parse_sequence(i : &mut usize, tokens : &Vec<Token>) -> Result<ast::Pattern,
                 patterns.push(p);
                 backtrack = *i;
             },
-            Err(_) => {
+            // A failure past the start of the prefix is a malformed prefix
+            // rather than the end of the sequence.
+            Err(x) => {
+                if x.0 > backtrack {
+                    return Err(x);
+                }
                 *i = backtrack;
                 break;
             }
@@ -239,7 +1359,7 @@ fn parse_sequence(i : &mut usize, tokens : &Vec<Token>) -> Result<ast::Pattern,
     }
 }
 
-fn parse_prefix(i : &mut usize, tokens : &Vec<Token>) -> Result<ast::Pattern, usize> {
+fn parse_prefix(i : &mut usize, tokens : &Tokens) -> Result<ast::Pattern, Failure> {
     let mut lookahead = None;
 
     if tokens.get(*i) == Some(&Token::Ambersand) {
@@ -261,11 +1381,32 @@ fn parse_prefix(i : &mut usize, tokens : &Vec<Token>) -> Result<ast::Pattern, us
     }
 }
 
-fn parse_suffix(i : &mut usize, tokens : &Vec<Token>) -> Result<ast::Pattern, usize> {
+// A label after the suffix, as in `expr^missing_expr`, is thrown when the
+// pattern fails.
+fn parse_suffix(i : &mut usize, tokens : &Tokens) -> Result<ast::Pattern, Failure> {
+    let pattern = parse_repetition_suffix(i, tokens)?;
+    if tokens.get(*i) != Some(&Token::Caret) {
+        return Ok(pattern);
+    }
+    *i += 1;
+    match tokens.get(*i) {
+        Some(Token::Name(name)) if !name.contains('.') => {
+            *i += 1;
+            Ok(ast::Pattern::Label(Box::new(pattern), name.clone(), 0))
+        },
+        _ => Err((*i, "a label after '^'"))
+    }
+}
+
+fn parse_repetition_suffix(i : &mut usize, tokens : &Tokens) -> Result<ast::Pattern, Failure> {
     match parse_primary(i, tokens) {
         Ok(p) => {
             if let Some(token) = tokens.get(*i) {
                 match token {
+                    &Token::OpenBrace if is_repetition(*i, tokens) => {
+                        let (min, max) = parse_repetition(i, tokens)?;
+                        Ok(ast::Pattern::Repeat(Box::new(p), min, max))
+                    },
                     &Token::Plus => {
                         *i += 1;
                         Ok(ast::Pattern::OneOrMore(Box::new(p)))
@@ -286,28 +1427,69 @@ fn parse_suffix(i : &mut usize, tokens : &Vec<Token>) -> Result<ast::Pattern, us
     }
 }
 
-fn parse_primary(i : &mut usize, tokens : &Vec<Token>) -> Result<ast::Pattern, usize> {
+// A brace starts a rule body unless it holds the bounds of a repetition.
+fn is_repetition(i : usize, tokens : &Tokens) -> bool {
+    tokens.get(i) == Some(&Token::OpenBrace) && matches!(tokens.get(i + 1), Some(&Token::Number(_)) | Some(&Token::Comma))
+}
+
+fn parse_repetition(i : &mut usize, tokens : &Tokens) -> Result<(u32, Option<u32>), Failure> {
+    *i += 1;
+    let min = match tokens.get(*i) {
+        Some(&Token::Number(n)) => {
+            *i += 1;
+            Some(n as u32)
+        },
+        _ => None
+    };
+    let max = if tokens.get(*i) == Some(&Token::Comma) {
+        *i += 1;
+        match tokens.get(*i) {
+            Some(&Token::Number(n)) => {
+                if (n as u32) < min.unwrap_or(0) {
+                    return Err((*i, "a maximum no less than the minimum"));
+                }
+                *i += 1;
+                Some(n as u32)
+            },
+            _ if min.is_none() => return Err((*i, "a maximum repetition count")),
+            _ => None
+        }
+    } else {
+        min
+    };
+    match tokens.get(*i) {
+        Some(&Token::CloseBrace) => *i += 1,
+        _ => return Err((*i, "'}' to end the repetition"))
+    }
+    Ok((min.unwrap_or(0), max))
+}
+
+fn parse_primary(i : &mut usize, tokens : &Tokens) -> Result<ast::Pattern, Failure> {
     let backtrack = *i;
     if let Some(token) = tokens.get(*i) {
         match token {
-            &Token::Name(id) => {
+            Token::Name(name) => {
+                let span = tokens.span(*i);
                 *i += 1;
-                if tokens.get(*i) != Some(&Token::OpenBrace) {
-                    if tokens.get(*i) == Some(&Token::Colon) {
+                if tokens.get(*i) != Some(&Token::OpenBrace) || is_repetition(*i, tokens) {
+                    if tokens.get(*i) == Some(&Token::OpenAngle) {
+                        let arguments = parse_arguments(i, tokens)?;
+                        Ok(ast::Pattern::Apply(name.clone(), arguments, span))
+                    } else if tokens.get(*i) == Some(&Token::Colon) {
                         *i += 1;
                         match tokens.get(*i) {
                             Some(&Token::Number(num)) => {
                                 *i += 1;
-                                Ok(ast::Pattern::Variable(id, num, 0, false))
+                                Ok(ast::Pattern::Reference(name.clone(), num, span))
                             },
-                            _ => Err(*i)
+                            _ => Err((*i, "a precedence after ':'"))
                         }
                     } else {
-                        Ok(ast::Pattern::Variable(id, -1, 0, false))
+                        Ok(ast::Pattern::Reference(name.clone(), -1, span))
                     }
                 } else {
                     *i = backtrack;
-                    Err(*i)
+                    Err((*i, "an expression"))
                 }
             },
             &Token::OpenParen => {
@@ -318,7 +1500,7 @@ fn parse_primary(i : &mut usize, tokens : &Vec<Token>) -> Result<ast::Pattern, u
                             *i += 1;
                             Ok(p)
                         } else {
-                            Err(*i)
+                            Err((*i, "')'"))
                         }
                     },
                     Err(x) => {
@@ -327,6 +1509,19 @@ fn parse_primary(i : &mut usize, tokens : &Vec<Token>) -> Result<ast::Pattern, u
                     }
                 }
             },
+            &Token::Atomic => {
+                *i += 1;
+                if tokens.get(*i) != Some(&Token::OpenParen) {
+                    return Err((*i, "'(' after `@atomic`"));
+                }
+                match parse_primary(i, tokens) {
+                    Ok(p) => Ok(ast::Pattern::Atomic(Box::new(p))),
+                    Err(x) => {
+                        *i = backtrack;
+                        Err(x)
+                    }
+                }
+            },
             &Token::SingleQuote | &Token::DoubleQuote => {
                 match parse_literal(i, tokens) {
                     Ok(p) => Ok(p),
@@ -349,20 +1544,52 @@ fn parse_primary(i : &mut usize, tokens : &Vec<Token>) -> Result<ast::Pattern, u
                 *i += 1;
                 Ok(ast::Pattern::CharAny)
             },
-            _ => Err(*i)
+            Token::Ranges(text, data) => {
+                *i += 1;
+                Ok(ast::Pattern::Shorthand(text.clone(), data.clone()))
+            },
+            &Token::Tilde => {
+                *i += 1;
+                Ok(ast::Pattern::Cut(tokens.span(*i - 1)))
+            },
+            // `$name:e` captures the text matched by `e` under the name, and
+            // `$name` on its own matches that text again. Captures are only
+            // seen by the rule that makes them, until it returns.
+            &Token::Dollar => {
+                let span = tokens.span(*i);
+                *i += 1;
+                let name = match tokens.get(*i) {
+                    Some(Token::Name(name)) if !name.contains('.') => name.clone(),
+                    _ => return Err((*i, "a capture name after '$'"))
+                };
+                *i += 1;
+                if tokens.get(*i) == Some(&Token::Colon) {
+                    *i += 1;
+                    let pattern = parse_suffix(i, tokens)?;
+                    Ok(ast::Pattern::Capture(Box::new(pattern), name, 0))
+                } else {
+                    Ok(ast::Pattern::BackReference(name, 0, span))
+                }
+            },
+            _ => Err((*i, "an expression"))
         }
     } else {
-        Err(*i)
+        Err((*i, "an expression"))
     }
 }
 
-fn parse_literal(i : &mut usize, tokens : &Vec<Token>) -> Result<ast::Pattern, usize> {
+fn parse_literal(i : &mut usize, tokens : &Tokens) -> Result<ast::Pattern, Failure> {
     let left_quote = tokens.get(*i);
     *i += 1;
     let mut letters = vec![];
     while let Some(token) = tokens.get(*i) {
         match token {
             &Token::Letter(x) => {
+                *i += 1;
+                let mut buffer = [0; 4];
+                letters.extend_from_slice(x.encode_utf8(&mut buffer).as_bytes());
+            },
+            &Token::Byte(x) => {
                 *i += 1;
                 letters.push(x);
             },
@@ -370,7 +1597,6 @@ fn parse_literal(i : &mut usize, tokens : &Vec<Token>) -> Result<ast::Pattern, u
         }
     }
     let right_quote = tokens.get(*i);
-    *i += 1;
     let quote_test = left_quote.is_some()
         && right_quote.is_some()
         && left_quote == right_quote
@@ -378,22 +1604,37 @@ fn parse_literal(i : &mut usize, tokens : &Vec<Token>) -> Result<ast::Pattern, u
             || *left_quote.unwrap() == Token::DoubleQuote);
 
     if quote_test {
-        Ok(ast::Pattern::CharSequence(letters))
+        *i += 1;
+        let insensitive = tokens.get(*i) == Some(&Token::Insensitive);
+        if insensitive {
+            *i += 1;
+        }
+        Ok(ast::Pattern::CharSequence(letters, insensitive))
     } else {
-        Err(*i)
+        Err((*i, "a matching closing quote"))
     }
 }
 
-fn parse_class(i : &mut usize, tokens : &Vec<Token>) -> Result<ast::Pattern, usize> {
+fn parse_class(i : &mut usize, tokens : &Tokens) -> Result<ast::Pattern, Failure> {
     match tokens.get(*i) {
         Some(&Token::OpenBracket) => *i += 1,
-        _ => return Err(*i)
+        _ => return Err((*i, "'['"))
+    }
+
+    let negated = tokens.get(*i) == Some(&Token::Caret);
+    if negated {
+        *i += 1;
     }
 
-    let mut ranges = vec![];
+    let mut items = vec![];
     loop {
         let left_letter = match tokens.get(*i) {
             Some(&Token::Letter(x)) => x,
+            Some(Token::Ranges(text, data)) => {
+                *i += 1;
+                items.push(ast::ClassItem::Named(text.clone(), data.clone()));
+                continue;
+            },
             _ => break
         };
         *i += 1;
@@ -403,22 +1644,27 @@ fn parse_class(i : &mut usize, tokens : &Vec<Token>) -> Result<ast::Pattern, usi
             *i += 1;
             right_letter = match tokens.get(*i) {
                 Some(&Token::Letter(x)) => Some(x),
-                _ => return Err(*i)
-            }
+                _ => return Err((*i, "a character after '-'"))
+            };
+            *i += 1;
         }
 
-        ranges.push((left_letter, right_letter));
+        items.push(ast::ClassItem::Range(left_letter, right_letter));
     }
 
     match tokens.get(*i) {
         Some(&Token::CloseBracket) => *i += 1,
-        _ => return Err(*i)
+        _ => return Err((*i, "a character or ']'"))
     }
 
-    if ranges.len() > 0 {
-        Ok(ast::Pattern::CharClass(ranges))
+    if !items.is_empty() {
+        let insensitive = tokens.get(*i) == Some(&Token::Insensitive);
+        if insensitive {
+            *i += 1;
+        }
+        Ok(ast::Pattern::Class(items, negated, insensitive))
     } else {
-        Err(*i)
+        Err((*i - 1, "a character"))
     }
 }
 
@@ -426,6 +1672,7 @@ fn parse_class(i : &mut usize, tokens : &Vec<Token>) -> Result<ast::Pattern, usi
 mod tests {
     use super::*;
     use machine;
+    use std::path::Path;
 
     fn execute_test(grammar : &str, subjects : &Vec<&str>, expected : &Vec<bool>) {
         let machine_result = machine::Machine::<String>::new(grammar);
@@ -447,29 +1694,161 @@ mod tests {
             main { a b c / b c a / c b a }
             a { apple+ }
             b { \"bu\"* }
-            c { [a-\\\"c]? }
+            c { [\\\"-ac]? }
             apple { &.!(\" \") }
         ";
 
-        let tokens = tokenize(&grammar).0;
+        let tokens = tokenize(grammar).ok().unwrap().tokens;
         println!("{:?}", tokens);
         let expected = vec![
-            Token::Name(0), Token::OpenBrace, Token::Name(1), Token::Name(2), Token::Name(3),
-                Token::Slash, Token::Name(2), Token::Name(3), Token::Name(1), Token::Slash,
-                Token::Name(3), Token::Name(2), Token::Name(1), Token::CloseBrace,
-            Token::Name(1), Token::OpenBrace, Token::Name(4), Token::Plus, Token::CloseBrace,
-            Token::Name(2), Token::OpenBrace, Token::DoubleQuote, Token::Letter(b'b'),
-                Token::Letter(b'u'), Token::DoubleQuote, Token::Asterik, Token::CloseBrace,
-            Token::Name(3), Token::OpenBrace, Token::OpenBracket, Token::Letter(b'a'),
-                Token::Dash, Token::Letter(b'\"'), Token::Letter(b'c'), Token::CloseBracket,
+            Token::Name("main".to_string()), Token::OpenBrace, Token::Name("a".to_string()), Token::Name("b".to_string()), Token::Name("c".to_string()),
+                Token::Slash, Token::Name("b".to_string()), Token::Name("c".to_string()), Token::Name("a".to_string()), Token::Slash,
+                Token::Name("c".to_string()), Token::Name("b".to_string()), Token::Name("a".to_string()), Token::CloseBrace,
+            Token::Name("a".to_string()), Token::OpenBrace, Token::Name("apple".to_string()), Token::Plus, Token::CloseBrace,
+            Token::Name("b".to_string()), Token::OpenBrace, Token::DoubleQuote, Token::Letter('b'),
+                Token::Letter('u'), Token::DoubleQuote, Token::Asterik, Token::CloseBrace,
+            Token::Name("c".to_string()), Token::OpenBrace, Token::OpenBracket, Token::Letter('\"'),
+                Token::Dash, Token::Letter('a'), Token::Letter('c'), Token::CloseBracket,
                 Token::Question, Token::CloseBrace,
-            Token::Name(4), Token::OpenBrace, Token::Ambersand, Token::Dot, Token::Exclamation,
-                Token::OpenParen, Token::DoubleQuote, Token::Letter(b' '), Token::DoubleQuote,
+            Token::Name("apple".to_string()), Token::OpenBrace, Token::Ambersand, Token::Dot, Token::Exclamation,
+                Token::OpenParen, Token::DoubleQuote, Token::Letter(' '), Token::DoubleQuote,
                 Token::CloseParen, Token::CloseBrace
         ];
         assert!(tokens.iter().eq(expected.iter()));
     }
 
+    #[test]
+    fn comments() {
+        let grammar = "
+            // A rule may be preceded by comments, which can mention 'quotes',
+            // [brackets] and slashes / without affecting the grammar.
+            main { a /* inline */ b // trailing
+                / b }
+            /* Block comments
+               span lines. */
+            a { '//' / [/*] }
+            b { \"/* not a comment */\" }
+        ";
+        let subjects = vec!["///* not a comment */", "*/* not a comment */", "/* not a comment */", "a"];
+        let expected = vec![true, true, true, false];
+        execute_test(grammar, &subjects, &expected);
+    }
+
+    #[test]
+    fn comments_do_not_move_errors() {
+        let plain = "main { a } a { 'a' / }";
+        let commented = "// leading
+            main { a } /* between */ a { 'a' / // dangling
+            }";
+        let plain_error = machine::Machine::<String>::new(plain).err().unwrap().remove(0);
+        let commented_error = machine::Machine::<String>::new(commented).err().unwrap().remove(0);
+        assert_eq!(plain_error.message, commented_error.message);
+        assert_eq!(plain_error.span, Span { line: 1, column: 22 });
+        assert_eq!(commented_error.span, Span { line: 3, column: 13 });
+    }
+
+    #[test]
+    fn grammar_errors() {
+        let cases = vec![
+            ("main { 'a' }\nb { 'b' ", (2, 9), Some("b"), Some("'}' to end the rule")),
+            ("main { a }\na { ('a' 'b' }", (2, 14), Some("a"), Some("')'")),
+            ("main { x:y }", (1, 10), Some("main"), Some("a precedence after ':'")),
+            ("main { [a-] }", (1, 11), Some("main"), Some("a character after '-'")),
+            ("main { [a-cz-a] }", (1, 12), None, Some("a range in increasing order")),
+            ("main 'a'", (1, 6), Some("main"), Some("'{' to begin the rule")),
+            ("main { 'a' } x", (1, 15), Some("x"), Some("'{' to begin the rule")),
+            ("main { 'a' }   x", (1, 17), Some("x"), Some("'{' to begin the rule")),
+            ("main { 'a' } }", (1, 14), None, Some("a rule definition")),
+            ("@atomic { 'a' }", (1, 9), None, Some("a rule name after `@atomic`")),
+            ("@atomic @silent { 'a' }", (1, 17), None, Some("a rule name after `@silent`")),
+            ("@fast main { 'a' }", (1, 2), None, Some("a modifier")),
+            ("main { 'a }", (1, 8), None, Some("a closing quote")),
+            ("main { [a }", (1, 8), None, Some("']'")),
+            ("main { 'a' } /* open", (1, 14), None, Some("'*/'")),
+            ("main { 'a\\q' }", (1, 10), None, Some("an escape sequence")),
+            ("main { '\\x4' }", (1, 9), None, Some("two hex digits")),
+            ("main { [\\xff] }", (1, 9), None, Some("a character")),
+            ("main { '\\u{d800}' }", (1, 9), None, Some("a code point in the form `\\u{...}`")),
+            ("main { '\\u{110000}' }", (1, 9), None, Some("a code point in the form `\\u{...}`")),
+            ("main { '\\u41' }", (1, 9), None, Some("a code point in the form `\\u{...}`")),
+            ("main { 'a'{3,2} }", (1, 14), Some("main"), Some("a maximum no less than the minimum")),
+            ("main { 'a'{99999999999} }", (1, 12), None, Some("a smaller number")),
+            ("main { a:2147483648 } a { 'a' }", (1, 10), None, Some("a smaller number")),
+            ("main { 'a'{,} }", (1, 13), Some("main"), Some("a maximum repetition count")),
+            ("main { 'a'{2 'b' }", (1, 14), Some("main"), Some("'}' to end the repetition")),
+            ("main { 'a'^ }", (1, 13), Some("main"), Some("a label after '^'")),
+            ("main { 'a' /{} 'b' }", (1, 14), Some("main"), Some("a label name")),
+            ("main { $ }", (1, 10), Some("main"), Some("a capture name after '$'")),
+            ("main { @left 'a' }", (1, 14), Some("main"), Some("'(' before the precedence level")),
+            ("main { @right(1) '-' }", (1, 18), Some("main"), Some("an operator with its operands")),
+            ("main { @nonassoc(1) '-' main }", (1, 21), Some("main"), Some("an operator with its operands")),
+            ("@operators main { 'a' }", (1, 12), None, Some("'(' before the operand rule")),
+            ("@operators(a) main { 'a' }", (1, 22), Some("main"), Some("an operator level")),
+            ("@operators(a) main { @left }", (1, 28), Some("main"), Some("an expression")),
+            ("main { [[:alfa:]] }", (1, 9), None, Some("a class name such as `[:alpha:]`")),
+            ("main { \\q }", (1, 8), None, Some("a class shorthand")),
+            ("main { \\p{L} }", (1, 8), None, Some("`@utf8` before the property")),
+            ("@utf8 main { [\\p{Foo}] }", (1, 15), None, Some("a unicode property"))
+        ];
+        for (grammar, (line, column), rule, expected) in cases {
+            let errors = machine::Machine::<String>::new(grammar).err().unwrap();
+            assert_eq!(errors.len(), 1);
+            let error = &errors[0];
+            println!("{}", error);
+            assert_eq!(error.span, Span { line, column });
+            assert_eq!(error.rule, rule.map(|x| x.to_string()));
+            assert_eq!(error.expected, expected.map(|x| x.to_string()));
+        }
+
+        let errors = machine::Machine::<String>::new("main { [z-a] }").err().unwrap();
+        assert_eq!(errors[0].message, "range `z-a` is reversed");
+    }
+
+    #[test]
+    fn resolution_errors() {
+        let cases = vec![
+            ("main { expr } exp { 'a' }", (1, 8), "main", "rule `expr` is not defined, did you mean `exp`?"),
+            ("main { a } a { 'a' / zzz }", (1, 22), "a", "rule `zzz` is not defined"),
+            ("main { a } a { 'a' }\na { 'b' }", (2, 1), "a", "rule `a` is defined more than once, first at 1:12"),
+            ("mian { 'a' }", (1, 1), "", "the grammar has no `main` rule"),
+            ("main { a } @inline a { '(' a ')' / 'x' }", (1, 20), "a", "the inline rule `a` refers to itself"),
+            ("main { a:2 } @inline a { 'x' }", (1, 8), "main", "the inline rule `a` cannot take a precedence"),
+            ("main { @atomic('a' ~ 'b') / 'a' 'c' }", (1, 20), "main", "a cut must be in an alternative of a choice"),
+            ("main { ('a' ~ 'b')? / 'c' }", (1, 13), "main", "a cut must be in an alternative of a choice"),
+            ("main { 'a' ~ 'b' }", (1, 12), "main", "a cut must be in an alternative of a choice"),
+            ("main { $x:'a' $y }", (1, 15), "main", "capture `y` is not defined"),
+            ("main { e } e { @left(1) e '+' t / t } t { 'a' }", (1, 31), "e", "the operands of rule `e` must refer to `e`, found `t`"),
+            ("main { open close } open { '<' $t:[a-z]+ '>' } close { '</' $t '>' }", (1, 61), "close",
+                "capture `t` is made by another rule, not by rule `close`")
+        ];
+        for (grammar, (line, column), rule, message) in cases {
+            let errors = machine::Machine::<String>::new(grammar).err().unwrap();
+            assert_eq!(errors.len(), 1);
+            println!("{}", errors[0]);
+            assert_eq!(errors[0].span, Span { line, column });
+            assert_eq!(errors[0].rule.as_deref().unwrap_or(""), rule);
+            assert_eq!(errors[0].message, message);
+        }
+    }
+
+    #[test]
+    fn resolution_reports_every_error() {
+        let grammar = "
+            main { nmae value }
+            name { [a-z]+ }
+            name { [A-Z]+ }
+            skip { space }
+        ";
+        let errors = machine::Machine::<String>::new(grammar).err().unwrap();
+        let messages : Vec<&str> = errors.iter().map(|x| x.message.as_str()).collect();
+        assert_eq!(messages, vec![
+            "rule `name` is defined more than once, first at 3:13",
+            "rule `nmae` is not defined, did you mean `name`?",
+            "rule `value` is not defined",
+            "rule `space` is not defined"
+        ]);
+    }
+
     #[test]
     fn simple_char_grammar() {
         let grammar = "
@@ -539,6 +1918,653 @@ mod tests {
         execute_test(&grammar, &subjects, &expected);
     }
 
+    #[test]
+    fn skip_rule() {
+        let grammar = "
+            main { ('a' 'b')* }
+            skip { [ \\t] / '\\n' }
+        ";
+        let subjects = vec!["abab", "ab a b", " a\tb\n ab ", "a c b"];
+        let expected = vec![true, true, true, false];
+        execute_test(grammar, &subjects, &expected);
+    }
+
+    #[test]
+    fn skip_between_tokens() {
+        let grammar = "
+            main { ('if' / 'in' / [a-z] [0-9]) (',' main)? }
+            skip { [ ] }
+        ";
+        let subjects = vec!["if", " in , a 1", "b2,if", "i f", "i n,a1", "b2 , i f"];
+        let expected = vec![true, true, true, false, false, false];
+        execute_test(grammar, &subjects, &expected);
+
+        // A repeated terminal is a single token.
+        let grammar = "
+            main { w w 'x'{2,3} [0-9]* '!'? }
+            w { [a-z]+ }
+            skip { [ ] }
+        ";
+        let subjects = vec!["ab cd xx", " a  b xxx 12 !", "ab cd xx ", "abcd xx", "ab cd x x", "ab cd xx 1 2"];
+        let expected = vec![true, true, true, false, false, false];
+        execute_test(grammar, &subjects, &expected);
+    }
+
+    #[test]
+    fn skip_rule_with_comments() {
+        let grammar = "
+            main { (name ';')* }
+            name { 'a' 'b' / 'c' }
+            skip { ws / comment }
+            ws { [ \\t\\r\\n] }
+            comment { '#' (!'\\n' .)* }
+        ";
+        let subjects = vec!["ab;c;", "ab ; # done", "# start\nc; # c\n ab;", "# only", "ab # ;"];
+        let expected = vec![true, true, true, true, false];
+        execute_test(grammar, &subjects, &expected);
+    }
+
+    #[test]
+    fn invalid_skip_rule() {
+        let grammars = vec![
+            "main { 'a' } skip { [ ]* }",
+            "main { 'a' } skip { ws } ws { [ ]? }",
+            "main { 'a' } skip { [ ] } skip { [\\t] }"
+        ];
+        for grammar in grammars {
+            assert!(machine::Machine::<String>::new(grammar).is_err());
+        }
+    }
+
+    #[test]
+    fn atomic_rules() {
+        let grammar = "
+            main { (range / le)* }
+            @atomic range { [0-9] '-' [0-9] }
+            le { @atomic('<' '=') }
+            skip { [ ] }
+        ";
+        let subjects = vec!["1-2", " 1-2  <= 3-4 ", "<=1-2", "1 -2", "< =", "1- 2"];
+        let expected = vec![true, true, true, false, false, false];
+        execute_test(grammar, &subjects, &expected);
+    }
+
+    #[test]
+    fn utf8_literals_and_classes() {
+        let grammar = "
+            main { ('é' / [α-ω] / [~-¡] / número)+ }
+            número { [٠-٩] '€' }
+        ";
+        let subjects = vec!["é", "αβω", "~\u{7f}\u{80}¡", "٣€é", "e", "Ω", "¢", "٣"];
+        let expected = vec![true, true, true, true, false, false, false, false];
+        execute_test(grammar, &subjects, &expected);
+    }
+
+    #[test]
+    fn negated_classes() {
+        let grammar = "
+            main { '#' [^#\\\\]* '#' / [^a-z0-9]+ / [\\^] }
+        ";
+        let subjects = vec!["##", "#hi é😀#", "ÉÉ!", "^", "#ab", "#a##", "aB", "^^"];
+        let expected = vec![true, true, true, true, false, false, false, true];
+        execute_test(grammar, &subjects, &expected);
+    }
+
+    #[test]
+    fn negated_classes_with_skip() {
+        let grammar = "
+            main { [^a]+ }
+            skip { [ ] }
+        ";
+        let subjects = vec!["bc", " b c ", "é é", "b a", " "];
+        let expected = vec![true, true, true, false, false];
+        execute_test(grammar, &subjects, &expected);
+
+        let code_points = "
+            @utf8
+            main { [^ -~] . }
+        ";
+        let subjects = vec!["éé", "éa", "ab", "é"];
+        let expected = vec![true, true, false, false];
+        execute_test(code_points, &subjects, &expected);
+
+        // Without `@utf8` the subject is bytes, which need not be UTF-8.
+        let mut machine = machine::Machine::<String>::new("main { [^a]+ !. }").ok().unwrap();
+        assert!(machine.execute(vec![0xFF]).is_ok());
+        assert!(machine.execute(vec![0xC3, 0xFF, 0x80]).is_ok());
+        assert!(machine.execute(vec![0xFF, b'a']).is_err());
+        let mut machine = machine::Machine::<String>::new("main { [^é] [^é] !. }").ok().unwrap();
+        assert!(machine.execute(vec![0xC3, 0xA8]).is_ok());
+        assert!(machine.execute(vec![0xC3, 0xA9]).is_err());
+    }
+
+    #[test]
+    fn escapes() {
+        let grammar = "
+            main { ('\\x41\\u{e9}' / [\\x00-\\x1f] / '\\'\"' / \"\\\"'\" / '\\\\' / [\\]\\-\\^] / '\\u{1F600}')+ }
+        ";
+        let subjects = vec!["Aé", "\u{0}\t\u{1f}", "'\"", "\"'", "\\", "]-^", "😀", "A\\é", " ", "a", "A"];
+        let expected = vec![true, true, true, true, true, true, true, false, false, false, false];
+        execute_test(grammar, &subjects, &expected);
+
+        let mut machine = machine::Machine::<String>::new("main { '\\xff\\xd8' . }").ok().unwrap();
+        assert!(machine.execute(vec![0xFF, 0xD8, 0x00]).is_ok());
+        assert!(machine.execute(vec![0xC3, 0xBF, 0xD8, 0x00]).is_err());
+    }
+
+    #[test]
+    fn case_insensitive() {
+        let grammar = "
+            main { 'select'i s [a-z_]i+ s (\"from\"i s [^a-z]i+)? }
+            s { ' '+ }
+            i { 'é'i }
+        ";
+        let subjects = vec!["select a from 1", "SELECT Ab_C FROM 2", "Select x ", "sElEcT Z fRoM 9.", "select 1", "select a from b"];
+        let expected = vec![true, true, true, true, false, false];
+        execute_test(grammar, &subjects, &expected);
+
+        let grammar = "main { 'straße'i i } i { [α-γ]i } ";
+        let subjects = vec!["STRAßEα", "straßeΓ", "StRaßEβ", "STRASSEα", "straßeδ"];
+        let expected = vec![true, true, true, false, false];
+        execute_test(grammar, &subjects, &expected);
+
+        let grammar = "main { 'é'i+ }";
+        let subjects = vec!["é", "ÉéÉ", "e", "E"];
+        let expected = vec![true, true, false, false];
+        execute_test(grammar, &subjects, &expected);
+
+        let grammar = "@utf8 main { [\\u{0}-\\u{10ffff}]i [k]i [\\u{212a}]i !. }";
+        let subjects = vec!["xK\u{212a}", "\u{10ffff}kk", "x\u{212a}K", "xkx"];
+        let expected = vec![true, true, true, false];
+        execute_test(grammar, &subjects, &expected);
+
+        // Literals fold through the same case pairs as classes.
+        let grammar = "@utf8 main { 'k'i 'ok'i '\u{212a}'i !. }";
+        let subjects = vec!["kokk", "\u{212a}O\u{212a}K", "KOKk", "kok\u{212b}", "kox"];
+        let expected = vec![true, true, true, false, false];
+        execute_test(grammar, &subjects, &expected);
+    }
+
+    #[test]
+    fn bounded_repetition() {
+        let grammar = "
+            main { date / hex / octets / 'x'{,2} ';' }
+            date { [0-9]{4} '-' [0-9]{2} '-' [0-9]{2} }
+            hex { '#' digit{3,} }
+            digit { [0-9a-f] }
+            octets { ([0-9]{1,3} '.'){3} [0-9]{1,3} }
+        ";
+        let subjects = vec![
+            "2024-01-31", "#fff", "#abcdef01", "192.168.0.1", ";", "xx;",
+            "24-01-31", "2024-1-31", "#ff", "1.2.3", "1.2.3.4567", "xxx;"
+        ];
+        let expected = vec![true, true, true, true, true, true, false, false, false, false, false, false];
+        execute_test(grammar, &subjects, &expected);
+
+        let grammar = "main { 'a'{1000} 'b'{0} }";
+        let mut machine = machine::Machine::<String>::new(grammar).ok().unwrap();
+        assert!(machine.program.len() < 20);
+        assert!(machine.execute(vec![b'a'; 1000]).is_ok());
+        assert!(machine.execute(vec![b'a'; 999]).is_err());
+        assert!(machine.execute(vec![b'a'; 1001]).is_err());
+    }
+
+    #[test]
+    fn imports() {
+        let path = Path::new("./tests/grammars/imports.peg");
+        let mut machine = machine::Machine::<String>::from_path(path).ok().unwrap();
+        let subjects = ["abc", "x1,42,_y", "1a", "a,", ""];
+        let expected = [true, true, false, false, false];
+        for i in 0..expected.len() {
+            let result = machine.execute(subjects[i].to_string().into_bytes());
+            assert_eq!(result.is_ok(), expected[i]);
+        }
+        assert!(machine.rule_names.contains(&"lex.chars.digit".to_string()));
+
+        let grammar = "
+            import \"tests/grammars/lex.peg\" as lex
+            main { lex.number '.' lex.number }
+        ";
+        let subjects = vec!["1.5", "10.25", "1.", "a.b"];
+        let expected = vec![true, true, false, false];
+        execute_test(grammar, &subjects, &expected);
+
+        // Without an import of `a` the dot matches any character.
+        let grammar = "main { a.b } a { 'a' } b { 'b' }";
+        let subjects = vec!["a.b", "a-b", "ab"];
+        let expected = vec![true, true, false];
+        execute_test(grammar, &subjects, &expected);
+    }
+
+    #[test]
+    fn import_errors() {
+        let path = Path::new("./tests/grammars/cycle_a.peg");
+        let errors = machine::Machine::<String>::from_path(path).err().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].module, Some("./tests/grammars/cycle_b.peg".to_string()));
+        assert_eq!(errors[0].span, Span { line: 1, column: 1 });
+        assert_eq!(errors[0].message, "import cycle: ./tests/grammars/cycle_a.peg \
+            -> ./tests/grammars/cycle_b.peg -> ./tests/grammars/cycle_a.peg");
+
+        let grammar = "
+            import \"tests/grammars/broken.peg\" as broken
+            main { broken.word broken.ident }
+        ";
+        let errors = machine::Machine::<String>::new(grammar).err().unwrap();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].module, Some("tests/grammars/broken.peg".to_string()));
+        assert_eq!(errors[0].span, Span { line: 1, column: 15 });
+        assert_eq!(errors[0].rule, Some("broken.word".to_string()));
+        assert_eq!(errors[0].message, "rule `broken.letter` is not defined");
+        assert_eq!(format!("{}", errors[0]),
+            "tests/grammars/broken.peg:1:15: rule `broken.letter` is not defined (in rule `broken.word`)");
+        assert_eq!(errors[1].module, None);
+        assert_eq!(errors[1].span, Span { line: 3, column: 32 });
+        assert_eq!(errors[1].message, "rule `broken.ident` is not defined");
+
+        let grammar = "
+            import 'tests/grammars/missing.peg' as missing
+            main { 'a' }
+        ";
+        let errors = machine::Machine::<String>::new(grammar).err().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].module, None);
+        assert_eq!(errors[0].span, Span { line: 2, column: 13 });
+        assert!(errors[0].message.starts_with("cannot import `tests/grammars/missing.peg`"));
+
+        let grammar = "import 'tests/grammars/utf8.peg' as text main { text.letter }";
+        let errors = machine::Machine::<String>::new(grammar).err().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].module, Some("tests/grammars/utf8.peg".to_string()));
+        assert_eq!(errors[0].span, Span { line: 1, column: 1 });
+        assert_eq!(errors[0].message, "only the root grammar may use `@utf8`");
+
+        let grammar = "import 'tests/grammars/lex.peg' lex main { lex.ident }";
+        let errors = machine::Machine::<String>::new(grammar).err().unwrap();
+        assert_eq!(errors[0].expected, Some("`as` after the imported path".to_string()));
+    }
+
+    #[test]
+    fn parameterized_rules() {
+        let grammar = "
+            main { list<value, sep<','>> ';' }
+            value { parens<list<[a-z]+, sep<'|'>>> / [0-9]+ }
+            list<item, separator> { item (separator item)* }
+            sep<x> { ' '* x ' '* }
+            parens<x> { '(' x ')' }
+        ";
+        let subjects = vec!["1;", "1, 2,3;", "(a|b), 7, (c);", "1,;", "(1);", "(a,b);"];
+        let expected = vec![true, true, true, false, false, false];
+        execute_test(grammar, &subjects, &expected);
+
+        let mut machine = machine::Machine::<String>::new(grammar).ok().unwrap();
+        let mut result = machine.execute("1,2;".to_string().into_bytes()).ok().unwrap();
+        result.sort();
+        assert_eq!(result, vec![
+            ("list".to_string(), 0, 3),
+            ("main".to_string(), 0, 4),
+            ("sep".to_string(), 1, 2),
+            ("value".to_string(), 0, 1),
+            ("value".to_string(), 2, 3)
+        ]);
+        let instances = machine.rule_names.iter().filter(|x| x.as_str() == "sep").count();
+        assert_eq!(instances, 2);
+    }
+
+    #[test]
+    fn parameterized_rule_errors() {
+        let cases = vec![
+            ("main { pair<'a'> } pair<x, y> { x y }", (1, 8), "rule `pair` takes 2 arguments, found 1"),
+            ("main { pair } pair<x, y> { x y }", (1, 8), "rule `pair` takes 2 arguments, found 0"),
+            ("main { a<'a'> } a { 'a' }", (1, 8), "rule `a` does not take arguments"),
+            ("main { grow<'a'> } grow<x> { x grow<(x x)> }", (1, 32), "expanding rule `grow` does not terminate"),
+            ("main { p<'a'> } p<x> { x } p<y> { y }", (1, 28), "rule `p` is defined more than once, first at 1:17"),
+            ("main { p<'a'> } p<x> { x } p { 'p' }", (1, 28), "rule `p` is defined more than once, first at 1:17"),
+            ("main { p } p { 'p' } p<x> { x }", (1, 22), "rule `p` is defined more than once, first at 1:12")
+        ];
+        for (grammar, (line, column), message) in cases {
+            let errors = machine::Machine::<String>::new(grammar).err().unwrap();
+            println!("{:?}", errors);
+            assert_eq!(errors.len(), 1);
+            assert_eq!(errors[0].span, Span { line, column });
+            assert_eq!(errors[0].message, message);
+        }
+
+        let grammar = "main { nested<'a'> } nested<x> { '(' nested<x> ')' / x }";
+        let subjects = vec!["a", "((a))", "((a)"];
+        let expected = vec![true, true, false];
+        execute_test(grammar, &subjects, &expected);
+    }
+
+    #[test]
+    fn silent_and_inline_rules() {
+        let grammar = "
+            main { s (word s)+ }
+            @silent s { ws* }
+            @inline ws { ' ' / comma }
+            @inline comma { ',' }
+            @atomic @silent word { letter+ }
+            letter { [a-z] }
+        ";
+        let mut machine = machine::Machine::<String>::new(grammar).ok().unwrap();
+        let mut result = machine.execute(" ab, c".to_string().into_bytes()).ok().unwrap();
+        result.sort();
+        assert_eq!(result, vec![
+            ("letter".to_string(), 1, 2),
+            ("letter".to_string(), 2, 3),
+            ("letter".to_string(), 5, 6),
+            ("main".to_string(), 0, 6)
+        ]);
+        assert!(machine.execute_rule("letter", b"a".to_vec()).is_ok());
+        assert!(machine.execute_rule("comma", b",".to_vec()).is_err());
+
+        let grammar = "
+            main { list }
+            list { more / item }
+            @inline more { list ',' item }
+            item { [0-9] }
+        ";
+        let subjects = vec!["1", "1,2,3", "1,", ""];
+        let expected = vec![true, true, false, false];
+        execute_test(grammar, &subjects, &expected);
+
+        let errors = machine::Machine::<String>::new("@inline main { 'a' }").err().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].span, Span { line: 1, column: 9 });
+        assert_eq!(errors[0].message, "the `main` rule cannot be inline");
+    }
+
+    #[test]
+    fn execute_rule() {
+        let grammar = "
+            main { statement+ }
+            statement { name '=' value ';' }
+            value { name / [0-9]+ }
+            name { [a-z]+ }
+            sum { sum:1 '-' [0-9] / [0-9] }
+        ";
+        let mut machine = machine::Machine::<String>::new(grammar).ok().unwrap();
+        assert!(machine.execute("a=1;b=a;".to_string().into_bytes()).is_ok());
+        assert!(machine.execute("b=a".to_string().into_bytes()).is_err());
+
+        let mut result = machine.execute_rule("statement", "b=a;".to_string().into_bytes()).ok().unwrap();
+        result.sort();
+        assert_eq!(result, vec![
+            ("name".to_string(), 0, 1),
+            ("name".to_string(), 2, 3),
+            ("statement".to_string(), 0, 4),
+            ("value".to_string(), 2, 3)
+        ]);
+        assert!(machine.execute_rule("value", "42".to_string().into_bytes()).is_ok());
+        assert!(machine.execute_rule("statement", "a=1;b=a;".to_string().into_bytes()).is_err());
+        assert!(machine.execute_rule("sum", "3-2-1".to_string().into_bytes()).is_ok());
+        assert!(machine.execute_rule("sum", "3-2-".to_string().into_bytes()).is_err());
+        match machine.execute_rule("valeu", "1".to_string().into_bytes()) {
+            Err(machine::Error::RuleError(name)) => assert_eq!(name, "valeu"),
+            x => panic!("unexpected result {:?}", x)
+        }
+    }
+
+    #[test]
+    fn cut() {
+        let grammar = "
+            main { statement+ }
+            statement { 'if' ~ ' ' name ~ ';' / name ';' / scoped }
+            scoped { ('<' ~ name '>' / '<' '>') / '<>>' }
+            name { [a-z]+ }
+        ";
+        let subjects = vec!["if x;", "x;", "ifx;", "iffy;", "if x;y;", "<a>", "<>>", "<>"];
+        let expected = vec![true, true, false, false, true, true, true, false];
+        execute_test(grammar, &subjects, &expected);
+
+        let grammar = "main { statement+ } statement { 'if' ' ' name ';' / name ';' } name { [a-z]+ }";
+        let subjects = vec!["if x;", "ifx;"];
+        let expected = vec![true, true];
+        execute_test(grammar, &subjects, &expected);
+    }
+
+    #[test]
+    fn labeled_failures() {
+        let grammar = "
+            main { statement* }
+            statement { 'let ' name^missing_name '=' value^missing_value ';'^missing_semicolon / 'letter' }
+            name { [a-z]+ }
+            value { [0-9]+ / name }
+        ";
+        let mut machine = machine::Machine::<String>::new(grammar).ok().unwrap();
+        assert!(machine.execute("let a=1;let b=a;".to_string().into_bytes()).is_ok());
+        assert_eq!(machine.labels, vec!["missing_name", "missing_value", "missing_semicolon"]);
+        let cases = vec![
+            ("let =1;", "missing_name", 4),
+            ("let a=;", "missing_value", 6),
+            ("let a=1;let b=2", "missing_semicolon", 15),
+            ("let 1", "missing_name", 4)
+        ];
+        for (subject, label, position) in cases {
+            match machine.execute(subject.to_string().into_bytes()) {
+                Err(machine::Error::LabelError(x, i)) => assert_eq!((x.as_str(), i), (label, position)),
+                x => panic!("unexpected result {:?}", x)
+            }
+        }
+        match machine.execute("x".to_string().into_bytes()) {
+            Err(machine::Error::MachineError(0)) => { },
+            x => panic!("unexpected result {:?}", x)
+        }
+    }
+
+    #[test]
+    fn failure_recovery() {
+        let grammar = "
+            main { statement* }
+            statement { 'let ' name '=' value^missing_value ';'^missing_semicolon /{missing_semicolon} error }
+            error { (!';' .)* ';' }
+            name { [a-z]+ }
+            value { [0-9]+ }
+        ";
+        let mut machine = machine::Machine::<String>::new(grammar).ok().unwrap();
+        let mut result = machine.execute("let a=1 let b=2;let c=3;".to_string().into_bytes()).ok().unwrap();
+        result.retain(|x| x.0 == "error" || x.0 == "statement");
+        result.sort();
+        assert_eq!(result, vec![
+            ("error".to_string(), 7, 16),
+            ("statement".to_string(), 0, 16),
+            ("statement".to_string(), 16, 24)
+        ]);
+        match machine.execute("let a=;".to_string().into_bytes()) {
+            Err(machine::Error::LabelError(x, 6)) => assert_eq!(x, "missing_value"),
+            x => panic!("unexpected result {:?}", x)
+        }
+
+        let grammar = "main { ('a' 'b'^x / 'c' 'd'^y) /{x, y} [a-z] '!' }";
+        let subjects = vec!["ab", "cd", "ac!", "cc!", "ac", "e!"];
+        let expected = vec![true, true, true, true, false, false];
+        execute_test(grammar, &subjects, &expected);
+    }
+
+    #[test]
+    fn back_references() {
+        let grammar = "main { 'r' $hashes:'#'* '\"' (!('\"' $hashes) .)* '\"' $hashes }";
+        let subjects = vec!["r\"abc\"", "r#\"a\"b\"#", "r##\"a\"#b\"##", "r#\"a\"##", "r#\"abc\"", "r##\"a\"#"];
+        let expected = vec![true, true, true, false, false, false];
+        execute_test(grammar, &subjects, &expected);
+
+        let grammar = "
+            main { element }
+            element { '<' $tag:name '>' (element / [a-z ])* '</' $tag '>' }
+            name { [a-z]+ }
+        ";
+        let subjects = vec!["<a>x<b>y</b></a>", "<a><a></a></a>", "<a><b></a></b>", "<a>x</b>", "<ab></a>"];
+        let expected = vec![true, true, false, false, false];
+        execute_test(grammar, &subjects, &expected);
+
+        // A capture made by an alternative that failed is forgotten.
+        let grammar = "main { ($x:'a' 'b' / 'a') $x }";
+        let subjects = vec!["aba", "aa", "a"];
+        let expected = vec![true, false, false];
+        execute_test(grammar, &subjects, &expected);
+
+        // Each attempt to grow a left-recursive rule starts without the
+        // captures of the attempts before it.
+        let grammar = "main { e !. } e { e:1 $x '!' / e:1 '+' $x:[a-z] $x / $x:[a-z] }";
+        let subjects = vec!["a", "a+bb", "a+bb+cc", "aa!", "a+bbb!"];
+        let expected = vec![true, true, true, false, false];
+        execute_test(grammar, &subjects, &expected);
+
+        // The skipped text before a capture is not part of it.
+        let grammar = "main { $x:'a' $x } skip { [ ] }";
+        let subjects = vec!["a a", " a a", "  a  a ", "aa", "a b"];
+        let expected = vec![true, true, true, true, false];
+        execute_test(grammar, &subjects, &expected);
+    }
+
+    #[test]
+    fn class_shorthands() {
+        let grammar = "main { \\w+ '=' [\\d.]+ \\s+ [[:xdigit:]_]+ }";
+        let subjects = vec!["ab_1=3.14 \t ff_0", "a-b=1 f", "a=1 g", "a=1f", "a=1 F"];
+        let expected = vec![true, false, false, false, true];
+        execute_test(grammar, &subjects, &expected);
+
+        let grammar = "main { [^\\s[:punct:]]+ }";
+        let subjects = vec!["abc", "a b", "a.b", "\u{e9}"];
+        let expected = vec![true, false, false, true];
+        execute_test(grammar, &subjects, &expected);
+
+        let grammar = "@utf8 main { \\p{Alphabetic}+ [\\p{N}_]* }";
+        let subjects = vec!["h\u{e9}llo\u{663}", "\u{3b1}\u{3b2}_2", "hello!", "1"];
+        let expected = vec![true, true, false, false];
+        execute_test(grammar, &subjects, &expected);
+
+        let grammar = "@utf8 main { \\p{Lu} \\p{L}* [\\p{Nd}\\p{Decimal_Number}]+ \\p{P}? }";
+        let subjects = vec!["\u{c9}t\u{e9}42", "Ab\u{663}\u{bf}", "A1-", "ab1", "A\u{bd}", "A1+"];
+        let expected = vec![true, true, true, false, false, false];
+        execute_test(grammar, &subjects, &expected);
+
+        // Only a well-formed `[:name:]` is a named class.
+        let grammar = "main { [[:]+ [[:a] }";
+        let subjects = vec!["[:[a", ":a", "[:b"];
+        let expected = vec![true, true, false];
+        execute_test(grammar, &subjects, &expected);
+    }
+
+    fn nested_spans(grammar : &str, subject : &str) -> Vec<(usize, usize)> {
+        let mut machine = machine::Machine::<String>::new(grammar).ok().unwrap();
+        let mut result : Vec<(usize, usize)> = machine.execute(subject.to_string().into_bytes()).ok().unwrap()
+            .drain(..)
+            .filter(|x| x.0 == "expr" && x.2 - x.1 > 1)
+            .map(|x| (x.1, x.2))
+            .collect();
+        result.sort();
+        result
+    }
+
+    #[test]
+    fn associativity() {
+        let grammar = "
+            main { expr }
+            expr {
+                @nonassoc(1) expr '<' expr
+                / @left(2) expr '-' expr
+                / @right(4) expr '^' expr
+                / @right(3) '-' expr
+                / [0-9]
+            }
+        ";
+        // Left: (1-2)-3
+        assert_eq!(nested_spans(grammar, "1-2-3"), vec![(0, 3), (0, 5)]);
+        // Right: 1^(2^3)
+        assert_eq!(nested_spans(grammar, "1^2^3"), vec![(0, 5), (2, 5)]);
+        // Mixed levels: (1-(2^3))-4 and (1^2)-3
+        assert_eq!(nested_spans(grammar, "1-2^3-4"), vec![(0, 5), (0, 7), (2, 5)]);
+        assert_eq!(nested_spans(grammar, "1^2-3"), vec![(0, 3), (0, 5)]);
+        // Prefix: -(1^2) and (-1)-2
+        assert_eq!(nested_spans(grammar, "-1^2"), vec![(0, 4), (1, 4)]);
+        assert_eq!(nested_spans(grammar, "-1-2"), vec![(0, 2), (0, 4)]);
+        // Non-associative: (1-2)<(3-4), while a chain is an error
+        assert_eq!(nested_spans(grammar, "1-2<3-4"), vec![(0, 3), (0, 7), (4, 7)]);
+        let subjects = vec!["1<2", "1<2<3", "1<2-3<4"];
+        let expected = vec![true, false, false];
+        execute_test(grammar, &subjects, &expected);
+
+        // Non-associative operators at one level may not follow each other.
+        let grammar = "
+            main { expr }
+            expr {
+                @nonassoc(1) expr '<' expr
+                / @nonassoc(1) expr '==' expr
+                / @left(2) expr '+' expr
+                / [a-z]
+            }
+        ";
+        let subjects = vec!["a<b", "a==b+c", "a<b==c", "a==b<c", "a<b<c"];
+        let expected = vec![true, true, false, false, false];
+        execute_test(grammar, &subjects, &expected);
+    }
+
+    #[test]
+    fn operator_table() {
+        let grammar = "
+            main { expr }
+            @operators(digit) expr {
+                @nonassoc '<' / '>'
+                @left '-' / '+'
+                @prefix '-'
+                @right '^'
+                @postfix '!'
+            }
+            @inline digit { [0-9] }
+        ";
+        assert_eq!(nested_spans(grammar, "1-2+3"), vec![(0, 3), (0, 5)]);
+        assert_eq!(nested_spans(grammar, "1^2^3"), vec![(0, 5), (2, 5)]);
+        assert_eq!(nested_spans(grammar, "1-2^3-4"), vec![(0, 5), (0, 7), (2, 5)]);
+        assert_eq!(nested_spans(grammar, "-1^2"), vec![(0, 4), (1, 4)]);
+        assert_eq!(nested_spans(grammar, "-1-2"), vec![(0, 2), (0, 4)]);
+        // Postfix binds tighter than everything else: 1^(2!) and -(1!)
+        assert_eq!(nested_spans(grammar, "1^2!"), vec![(0, 4), (2, 4)]);
+        assert_eq!(nested_spans(grammar, "-1!!"), vec![(0, 4), (1, 3), (1, 4)]);
+        assert_eq!(nested_spans(grammar, "1-2<3+4"), vec![(0, 3), (0, 7), (4, 7)]);
+        let subjects = vec!["1>2", "1<2>3", "1<2-3<4", "1!-", "+1"];
+        let expected = vec![true, false, false, false, false];
+        execute_test(grammar, &subjects, &expected);
+
+        // The same operator may be both prefix and infix, but not at two
+        // levels of the same kind.
+        let conflicts = vec![
+            ("@operators(a) main { @left '+' @right '-' / '+' } a { 'a' }", (1, 45), "the operator '+' is already at level 1"),
+            ("@operators(a) main { @prefix op @postfix '!' @prefix op } a { 'a' } op { '-' }", (1, 54), "the operator `op` is already at level 1"),
+            ("@operators(a) main { @left '+' / '+' } a { 'a' }", (1, 34), "the operator '+' is already at level 1")
+        ];
+        for (grammar, (line, column), message) in conflicts {
+            let errors = machine::Machine::<String>::new(grammar).err().unwrap();
+            assert_eq!(errors.len(), 1);
+            assert_eq!(errors[0].span, Span { line, column });
+            assert_eq!(errors[0].message, message);
+        }
+        let grammar = "@operators(a) main { @prefix '-' @left '-' } a { 'a' }";
+        let subjects = vec!["-a-a", "--a", "a-"];
+        let expected = vec![true, true, false];
+        execute_test(grammar, &subjects, &expected);
+    }
+
+    #[test]
+    fn utf8_any() {
+        let bytes = "main { . . }";
+        let subjects = vec!["ab", "é", "éa", "😀"];
+        let expected = vec![true, true, false, false];
+        execute_test(bytes, &subjects, &expected);
+
+        let code_points = "
+            @utf8
+            main { . . }
+        ";
+        let subjects = vec!["ab", "é", "éa", "😀x", "\u{ffff}\u{10000}"];
+        let expected = vec![true, false, true, true, true];
+        execute_test(code_points, &subjects, &expected);
+
+        let mut machine = machine::Machine::<String>::new(code_points).ok().unwrap();
+        assert!(machine.execute(vec![0xFF, b'a']).is_err());
+        assert!(machine.execute(vec![0xC3, 0xA9, b'a']).is_ok());
+    }
+
     #[test]
     fn dogfood() {
         let grammar = "
@@ -593,6 +2619,119 @@ mod tests {
         execute_test(&grammar, &subjects, &expected);
     }
 
+    #[test]
+    fn format_layout() {
+        let grammar = "
+            // Sums of numbers.
+            main { s sum } // entry
+            sum {
+                // left-recursive
+                sum:1 plus sum:2 / num
+            }
+            @silent s { [ \\t]* }
+            plus {'+' s}
+            /* digits */ num { [0-9]+ s }
+            // the end
+        ";
+        let expected = "// Sums of numbers.
+main { s sum } // entry
+
+sum {
+    // left-recursive
+    sum:1 plus sum:2
+    / num
+}
+
+@silent s { [ \\t]* }
+plus { '+' s }
+
+/* digits */
+num { [0-9]+ s }
+
+// the end
+";
+        assert_eq!(format(grammar).unwrap(), expected);
+        assert_eq!(format(expected).unwrap(), expected);
+    }
+
+    #[test]
+    fn format_round_trip() {
+        let grammars = vec![
+            include_str!("../tests/grammars/calculator1.peg").to_string(),
+            "
+                @utf8
+                skip { ' '+ }
+                main { a (b / c)? d<'x' / [^a-z\\-]i> 'q'{2,} ('\\x80\\t' / 'é\\''i) }
+                a { @atomic('a' 'a') !'b' ~ 'a'* / 'b' }
+                b { ('b'^lb / 'c' ('d' 'e')^le)+ /{lb, le} 'z' }
+                c { $n:('c' [\\p{N}]) .? $n }
+                d<x> { x x:1 / x }
+            ".to_string(),
+            "
+                main { expr }
+                @operators(atom) expr {
+                    @nonassoc '<'
+                    @left '+' / '-' // additive
+                    @prefix '-'
+                    @right '^'
+                }
+                atom { [0-9] / '(' expr ')' }
+            ".to_string()
+        ];
+        // Grammars already in the canonical layout print as they are written,
+        // with their operators, tables and class shorthands.
+        let canonical = "@utf8
+
+main { expr !. }
+
+// Levels go from the loosest to the tightest.
+@operators(atom) expr {
+    @nonassoc '<' / '=='
+    @left '+' / '-' // additive
+    @prefix '-'
+    @postfix '!'
+    @right '^'
+}
+
+atom {
+    \\d+
+    / '(' cmp ')'
+}
+
+word { [\\w[:punct:]\\p{Lu}\\-]+ \\s* }
+
+cmp {
+    @nonassoc(1) cmp '<' cmp
+    / @nonassoc(1) cmp '==' cmp
+    / @right(2) '-' cmp
+    / expr
+}
+";
+        assert_eq!(format(canonical).unwrap(), canonical);
+        let grammars : Vec<String> = grammars.into_iter().chain(Some(canonical.to_string())).collect();
+
+        let subjects = vec![
+            "1", "1+2*3", "-(1-2)", "a-1", "2^3", "1<2-3", "1<2<3", "aa", "aaab", "bz", "bcdz", "c1.c1", "c1c2",
+            "aaxxqqé'", "aa x x qq É'", "aaxqq\t", "1!<2", "(1==2)<3", "(1<2==3)", "(-1)^2!"
+        ];
+        for grammar in grammars {
+            let formatted = format(&grammar).unwrap();
+            println!("{}", formatted);
+            assert_eq!(format(&formatted).unwrap(), formatted);
+
+            let mut original = machine::Machine::<String>::new(&grammar).ok().unwrap();
+            let mut copy = machine::Machine::<String>::new(&formatted).ok().unwrap();
+            // Left-recursive rules may record their captures in either order.
+            for subject in &subjects {
+                let subject = subject.to_string().into_bytes();
+                let mut expected = original.execute(subject.clone()).ok();
+                let mut result = copy.execute(subject).ok();
+                if let Some(x) = expected.as_mut() { x.sort() }
+                if let Some(x) = result.as_mut() { x.sort() }
+                assert_eq!(expected, result);
+            }
+        }
+    }
 }
 
 
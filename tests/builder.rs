@@ -0,0 +1,122 @@
+#[macro_use]
+extern crate pvm;
+
+use pvm::{Grammar, Machine, Pattern};
+
+fn execute_test(machine : &mut Machine<String>, subjects : &[&str], expected : &[bool]) {
+    assert!(subjects.len() == expected.len());
+    for i in 0..expected.len() {
+        let result = machine.execute(subjects[i].to_string().into_bytes());
+        println!("{}", subjects[i]);
+        println!("{:?}", result);
+        assert!(result.is_ok() == expected[i]);
+    }
+}
+
+#[test]
+fn arithmetic() {
+    let digit = Pattern::class(&[('0', '9')]);
+    let mut machine = Grammar::builder()
+        .rule("main", seq![Pattern::rule("s"), Pattern::rule("expr")])
+        .rule("expr", choice![
+            seq![Pattern::rule_at("expr", 1), "+", Pattern::rule("s"), Pattern::rule_at("expr", 2)],
+            seq![Pattern::rule_at("expr", 2), "*", Pattern::rule("s"), Pattern::rule_at("expr", 3)],
+            seq!["(", Pattern::rule("s"), Pattern::rule("expr"), ")", Pattern::rule("s")],
+            Pattern::rule("num")
+        ])
+        .rule("num", seq![digit.one_or_more(), Pattern::rule("s")])
+        .silent_rule("s", Pattern::literal(" ").zero_or_more())
+        .build().unwrap()
+        .machine::<String>();
+
+    let subjects = vec!["1", " 1 + 2", "1+2*3", "(1+2)*3", "1+", "(1"];
+    let expected = vec![true, true, true, true, false, false];
+    execute_test(&mut machine, &subjects, &expected);
+
+    let mut result = machine.execute(b"1+2*3".to_vec()).ok().unwrap();
+    result.retain(|x| x.0 == "expr");
+    result.sort();
+    assert_eq!(result, vec![
+        ("expr".to_string(), 0, 1), ("expr".to_string(), 0, 5), ("expr".to_string(), 2, 3),
+        ("expr".to_string(), 2, 5), ("expr".to_string(), 4, 5)
+    ]);
+}
+
+#[test]
+fn same_as_text() {
+    let text = "
+        skip { ' '+ }
+        main { list<word> !. }
+        list<x> { x (',' x)* }
+        word { $w:[a-z]i+ '=' $w / 'x'^missing /{missing} '?' }
+    ";
+    let word = choice![
+        seq![Pattern::class(&[('a', 'z')]).insensitive().one_or_more().capture("w"), "=", Pattern::back_reference("w")],
+        Pattern::literal("x").label("missing")
+    ];
+    let built = Grammar::builder()
+        .skip(Pattern::literal(" ").one_or_more())
+        .rule("main", seq![Pattern::apply("list", vec![Pattern::rule("word")]), Pattern::any().not_followed_by()])
+        .template("list", &["x"], seq![Pattern::rule("x"), seq![",", Pattern::rule("x")].zero_or_more()])
+        .rule("word", word.recover(&["missing"], Pattern::literal("?")))
+        .build().unwrap();
+
+    let mut from_text = Machine::<String>::new(text).ok().unwrap();
+    let mut from_builder = built.machine::<String>();
+    for subject in &["ab=ab", "aB=aB", "aB=Ab", "ab=ab ,c=c", "ab=ac", "x", "?", "y", "ab=ab,", ""] {
+        let subject = subject.to_string().into_bytes();
+        let mut expected = from_text.execute(subject.clone()).ok();
+        let mut result = from_builder.execute(subject).ok();
+        if let Some(x) = expected.as_mut() { x.sort() }
+        if let Some(x) = result.as_mut() { x.sort() }
+        assert_eq!(expected, result);
+    }
+    assert!(from_builder.execute(b"ab=ab ,c=c".to_vec()).is_ok());
+    assert!(from_builder.execute(b"aB=Ab".to_vec()).is_err());
+}
+
+#[test]
+fn validation() {
+    let errors = Grammar::builder()
+        .rule("main", seq![Pattern::rule("word"), Pattern::rule("two words")])
+        .rule("word", Pattern::literal("a").repeat(3, Some(2)))
+        .build().err().unwrap();
+    let messages : Vec<&str> = errors.iter().map(|x| x.message.as_str()).collect();
+    assert_eq!(messages, vec![
+        "`two words` is not a valid rule name",
+        "the repetition maximum 2 is less than the minimum 3"
+    ]);
+
+    let errors = Grammar::builder()
+        .rule("main", Pattern::rule("words"))
+        .rule("word", "a")
+        .rule("word", "b")
+        .build().err().unwrap();
+    let messages : Vec<&str> = errors.iter().map(|x| x.message.as_str()).collect();
+    assert_eq!(messages, vec![
+        "rule `word` is defined more than once",
+        "rule `words` is not defined, did you mean `word`?"
+    ]);
+    assert_eq!(errors[1].rule, Some("main".to_string()));
+
+    let errors = Grammar::builder()
+        .rule("main", seq![Pattern::class(&[('z', 'a')]), Pattern::rule_at("main", u32::MAX)])
+        .build().err().unwrap();
+    let messages : Vec<&str> = errors.iter().map(|x| x.message.as_str()).collect();
+    assert_eq!(messages, vec![
+        "range `z-a` is reversed",
+        "the precedence of `main` is larger than 2147483647"
+    ]);
+
+    let errors = Grammar::builder()
+        .rule("main", "a")
+        .skip(Pattern::literal(" ").zero_or_more())
+        .build().err().unwrap();
+    assert_eq!(errors[0].message, "the skip rule must not match empty input");
+
+    let errors = Grammar::builder()
+        .rule("main", "a")
+        .rule("skip", "b")
+        .build().err().unwrap();
+    assert_eq!(errors[0].message, "`skip` is not a valid rule name");
+}
@@ -18,9 +18,7 @@ enum Rules {
     Divide,
     Num,
     Open,
-    Close,
-    S,
-    Ws
+    Close
 }
 
 impl FromStr for Rules {
@@ -37,8 +35,6 @@ impl FromStr for Rules {
             "num" => Ok(Rules::Num),
             "open" => Ok(Rules::Open),
             "close" => Ok(Rules::Close),
-            "s" => Ok(Rules::S),
-            "ws" => Ok(Rules::Ws),
             _ => Err(0)
         }
     }
@@ -169,13 +165,10 @@ fn run_test(data : Vec<Syntax>) {
             for tree in data {
                 let input = tree.print();
                 println!("input: {}", input);
-                let mut temp = match machine.execute(input.to_string().into_bytes()) {
+                let mut result = match machine.execute(input.to_string().into_bytes()) {
                     Ok(x) => x,
                     Err(x) => panic!("Parse Error: {:?}", x)
                 };
-                let mut result = temp.drain(..)
-                    .filter(|x| x.0 != Rules::S && x.0 != Rules::Ws)
-                    .collect();
                 let new_tree = Syntax::parse(&input, &mut result);
                 println!("old: {:?}, new: {:?}", tree, new_tree);
                 let new_eval = new_tree.eval();
@@ -187,8 +180,10 @@ fn run_test(data : Vec<Syntax>) {
                 }
             }
         },
-        Err(x) => {
-            println!("Error: {}", x); 
+        Err(errors) => {
+            for x in errors {
+                println!("Error: {}", x);
+            }
             assert!(false);
         }
     }